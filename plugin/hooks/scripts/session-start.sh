@@ -6,7 +6,6 @@ CMS_BIN="claude-model-switch"
 INSTALL_DIR="$HOME/.local/bin"
 REPO="codejunkie99/claude-model-switch"
 CONFIG="$HOME/.claude/model-profiles.json"
-PID_FILE="$HOME/.claude/model-switch-proxy.pid"
 
 # Ensure install dir exists
 mkdir -p "$INSTALL_DIR"
@@ -63,15 +62,15 @@ if [ ! -f "$CONFIG" ]; then
   "$CMS_BIN" init 2>/dev/null || true
 fi
 
-# Start proxy if not running
-if [ -f "$PID_FILE" ]; then
-  PID=$(cat "$PID_FILE")
-  if ! kill -0 "$PID" 2>/dev/null; then
-    rm -f "$PID_FILE"
-    "$CMS_BIN" start 2>/dev/null || true
-  fi
-else
+# Start proxy unless a healthy one is already answering. `status --check`
+# asks the proxy's /healthz, so a stale PID file or a port held by another
+# program does not count as running. `start` clears a stale PID file itself.
+if ! "$CMS_BIN" status --check >/dev/null 2>&1; then
   "$CMS_BIN" start 2>/dev/null || true
 fi
 
-echo "[cms] Ready"
+if "$CMS_BIN" status --check >/dev/null 2>&1; then
+  echo "[cms] Ready"
+else
+  echo "[cms] Proxy is not healthy. Run: claude-model-switch status"
+fi
@@ -0,0 +1,218 @@
+//! Per-provider `anthropic-beta` and `anthropic-version` policy. Betas a
+//! provider rejects are rewritten or stripped, and request fields that only
+//! make sense with a stripped beta are dropped with it.
+
+use hyper::header::HeaderValue;
+use hyper::HeaderMap;
+use serde_json::Value;
+
+use crate::config::HeaderPolicy;
+
+/// Top-level body fields that depend on a beta, by beta name prefix.
+const BETA_FIELDS: [(&str, &str); 4] = [
+    ("context-management-", "context_management"),
+    ("mcp-client-", "mcp_servers"),
+    ("structured-outputs-", "output_format"),
+    ("skills-", "container"),
+];
+
+/// Server tool types that depend on a beta, by beta name prefix.
+const BETA_TOOLS: [(&str, &str); 3] = [
+    ("computer-use-", "computer_"),
+    ("code-execution-", "code_execution_"),
+    ("web-fetch-", "web_fetch_"),
+];
+
+/// `pattern` is a beta name, or a prefix ending in `*`.
+fn matches(pattern: &str, beta: &str) -> bool {
+    match pattern.strip_suffix('*') {
+        Some(prefix) => beta.starts_with(prefix),
+        None => pattern == beta,
+    }
+}
+
+/// The outcome of applying a policy to the inbound betas.
+#[derive(Debug, Default, PartialEq)]
+pub struct BetaDecision {
+    pub kept: Vec<String>,
+    pub stripped: Vec<String>,
+}
+
+impl HeaderPolicy {
+    fn allows(&self, beta: &str) -> bool {
+        let allowed = match &self.beta_allow {
+            Some(allow) => allow.iter().any(|p| matches(p, beta)),
+            None => true,
+        };
+        allowed && !self.beta_deny.iter().any(|p| matches(p, beta))
+    }
+
+    /// Rewrite, then allow or deny, each comma-separated beta in `headers`.
+    pub fn decide(&self, headers: &HeaderMap) -> BetaDecision {
+        let mut decision = BetaDecision::default();
+        let betas = headers
+            .get_all("anthropic-beta")
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .map(str::trim)
+            .filter(|beta| !beta.is_empty());
+        for inbound in betas {
+            let beta = self
+                .beta_rewrite
+                .get(inbound)
+                .map_or(inbound, String::as_str);
+            if beta.is_empty() {
+                decision.stripped.push(inbound.to_string());
+            } else if !self.allows(beta) {
+                decision.stripped.push(beta.to_string());
+            } else if !decision.kept.iter().any(|kept| kept == beta) {
+                decision.kept.push(beta.to_string());
+            }
+        }
+        decision
+    }
+
+    /// Replace the beta header with the kept betas and pin the version.
+    pub fn apply_headers(&self, headers: &mut HeaderMap, decision: &BetaDecision) {
+        headers.remove("anthropic-beta");
+        if !decision.kept.is_empty() {
+            if let Ok(value) = HeaderValue::from_str(&decision.kept.join(",")) {
+                headers.insert("anthropic-beta", value);
+            }
+        }
+        if let Some(version) = &self.anthropic_version {
+            if let Ok(value) = HeaderValue::from_str(version) {
+                headers.insert("anthropic-version", value);
+            }
+        }
+    }
+}
+
+/// Drop the body fields and server tools that need one of the `stripped`
+/// betas. Returns what was dropped, for the log.
+pub fn drop_dependent_fields(body: &mut Value, stripped: &[String]) -> Vec<String> {
+    let mut dropped = Vec::new();
+    let Some(map) = body.as_object_mut() else {
+        return dropped;
+    };
+    for beta in stripped {
+        for (prefix, field) in BETA_FIELDS {
+            if beta.starts_with(prefix) && map.remove(field).is_some() {
+                dropped.push(field.to_string());
+            }
+        }
+        for (prefix, tool_type) in BETA_TOOLS {
+            if !beta.starts_with(prefix) {
+                continue;
+            }
+            if let Some(Value::Array(tools)) = map.get_mut("tools") {
+                tools.retain(|tool| {
+                    let server_tool = tool
+                        .get("type")
+                        .and_then(Value::as_str)
+                        .filter(|t| t.starts_with(tool_type));
+                    if let Some(t) = server_tool {
+                        dropped.push(format!("tool {}", t));
+                    }
+                    server_tool.is_none()
+                });
+            }
+        }
+    }
+    let removed_tools = dropped.iter().any(|d| d.starts_with("tool "));
+    if removed_tools
+        && map
+            .get("tools")
+            .and_then(Value::as_array)
+            .is_some_and(Vec::is_empty)
+    {
+        map.remove("tools");
+        map.remove("tool_choice");
+    }
+    dropped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use std::collections::BTreeMap;
+
+    fn inbound(betas: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert("anthropic-beta", HeaderValue::from_str(betas).unwrap());
+        headers.insert("anthropic-version", HeaderValue::from_static("2023-06-01"));
+        headers
+    }
+
+    #[test]
+    fn rewrites_then_filters_betas() {
+        let policy = HeaderPolicy {
+            anthropic_version: Some("2023-01-01".to_string()),
+            beta_allow: Some(vec![
+                "fine-grained-tool-streaming-*".to_string(),
+                "new-*".to_string(),
+            ]),
+            beta_deny: vec!["new-bad".to_string()],
+            beta_rewrite: BTreeMap::from([("old-name".to_string(), "new-name".to_string())]),
+        };
+        let mut headers = inbound(
+            "interleaved-thinking-2025-05-14, fine-grained-tool-streaming-2025-05-14,old-name,new-bad",
+        );
+        let decision = policy.decide(&headers);
+        assert_eq!(
+            decision.kept,
+            ["fine-grained-tool-streaming-2025-05-14", "new-name"]
+        );
+        assert_eq!(
+            decision.stripped,
+            ["interleaved-thinking-2025-05-14", "new-bad"]
+        );
+
+        policy.apply_headers(&mut headers, &decision);
+        assert_eq!(
+            headers["anthropic-beta"],
+            "fine-grained-tool-streaming-2025-05-14,new-name"
+        );
+        assert_eq!(headers["anthropic-version"], "2023-01-01");
+
+        let deny_all = HeaderPolicy {
+            beta_deny: vec!["*".to_string()],
+            ..Default::default()
+        };
+        let mut headers = inbound("context-management-2025-06-27");
+        let decision = deny_all.decide(&headers);
+        assert_eq!(decision.stripped, ["context-management-2025-06-27"]);
+        deny_all.apply_headers(&mut headers, &decision);
+        assert!(headers.get("anthropic-beta").is_none());
+        assert_eq!(headers["anthropic-version"], "2023-06-01");
+    }
+
+    #[test]
+    fn drops_fields_of_stripped_betas() {
+        let mut body = json!({
+            "model": "m",
+            "context_management": {"edits": []},
+            "tools": [
+                {"type": "computer_20250124", "name": "computer"},
+                {"name": "read_file", "input_schema": {}}
+            ]
+        });
+        let dropped = drop_dependent_fields(
+            &mut body,
+            &[
+                "context-management-2025-06-27".to_string(),
+                "computer-use-2025-01-24".to_string(),
+            ],
+        );
+        assert_eq!(dropped, ["context_management", "tool computer_20250124"]);
+        assert!(body.get("context_management").is_none());
+        assert_eq!(body["tools"].as_array().unwrap().len(), 1);
+
+        let mut body =
+            json!({"tools": [{"type": "web_fetch_20250910"}], "tool_choice": {"type": "auto"}});
+        drop_dependent_fields(&mut body, &["web-fetch-2025-09-10".to_string()]);
+        assert_eq!(body, json!({}));
+    }
+}
@@ -0,0 +1,406 @@
+//! Daily and monthly spending caps, checked before a request is forwarded
+//! and updated as usage is recorded.
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+use crate::config::{Budget, ModelPrice, ProfileConfig};
+use crate::time::format_date;
+use crate::usage::{self, UsageRecord};
+
+/// USD spent in the current day and month.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Spend {
+    pub daily: f64,
+    pub monthly: f64,
+}
+
+impl Spend {
+    fn add(&mut self, cost: f64) {
+        self.daily += cost;
+        self.monthly += cost;
+    }
+}
+
+/// Outcome of checking a provider against its own and the global budget.
+#[derive(Debug, Clone, PartialEq)]
+pub enum BudgetCheck {
+    Allow,
+    Exceeded {
+        message: String,
+        reroute_to: Option<String>,
+    },
+}
+
+#[derive(Debug, Default)]
+struct Ledger {
+    /// UTC day (`YYYY-MM-DD`) the daily totals belong to.
+    day: String,
+    prices: HashMap<String, ModelPrice>,
+    global: Option<Budget>,
+    budgets: HashMap<String, Budget>,
+    total: Spend,
+    providers: HashMap<String, Spend>,
+}
+
+fn provider_budgets(config: &ProfileConfig) -> HashMap<String, Budget> {
+    config
+        .providers
+        .iter()
+        .filter_map(|(name, p)| Some((name.clone(), p.budget.clone()?)))
+        .collect()
+}
+
+impl Ledger {
+    fn new(config: &ProfileConfig, records: &[UsageRecord], now: SystemTime) -> Self {
+        let mut ledger = Self {
+            day: format_date(now),
+            prices: config.prices.clone(),
+            global: config.budget.clone(),
+            budgets: provider_budgets(config),
+            ..Default::default()
+        };
+        let month = &ledger.day[..7];
+        for record in records.iter().filter(|r| r.day.starts_with(month)) {
+            let Some(cost) = record.cost(&ledger.prices) else {
+                continue;
+            };
+            let today = record.day == ledger.day;
+            for spend in [
+                &mut ledger.total,
+                ledger.providers.entry(record.provider.clone()).or_default(),
+            ] {
+                spend.monthly += cost;
+                if today {
+                    spend.daily += cost;
+                }
+            }
+        }
+        ledger
+    }
+
+    /// Whether `config` has the budgets and prices this ledger was built from.
+    fn matches(&self, config: &ProfileConfig) -> bool {
+        self.prices == config.prices
+            && self.global == config.budget
+            && self.budgets == provider_budgets(config)
+    }
+
+    /// Reset totals when the UTC day or month has changed.
+    fn roll_over(&mut self, now: SystemTime) {
+        let day = format_date(now);
+        if day == self.day {
+            return;
+        }
+        let new_month = day[..7] != self.day[..7];
+        for spend in std::iter::once(&mut self.total).chain(self.providers.values_mut()) {
+            spend.daily = 0.0;
+            if new_month {
+                spend.monthly = 0.0;
+            }
+        }
+        self.day = day;
+    }
+
+    fn spend(&self, provider_name: &str) -> Spend {
+        self.providers
+            .get(provider_name)
+            .copied()
+            .unwrap_or_default()
+    }
+
+    fn check(&self, provider_name: &str) -> BudgetCheck {
+        if let Some(budget) = self.budgets.get(provider_name) {
+            let scope = format!("provider '{}'", provider_name);
+            if let Some(message) = exhausted(&scope, budget, self.spend(provider_name)) {
+                return BudgetCheck::Exceeded {
+                    message,
+                    reroute_to: budget.reroute_to.clone(),
+                };
+            }
+        }
+        if let Some(budget) = &self.global {
+            // The designated reroute target keeps serving once the global
+            // budget is gone; that is what it is there for.
+            if budget.reroute_to.as_deref() != Some(provider_name) {
+                if let Some(message) = exhausted("all providers", budget, self.total) {
+                    return BudgetCheck::Exceeded {
+                        message,
+                        reroute_to: budget.reroute_to.clone(),
+                    };
+                }
+            }
+        }
+        BudgetCheck::Allow
+    }
+}
+
+fn periods(budget: &Budget, spend: Spend) -> [(&'static str, Option<f64>, f64); 2] {
+    [
+        ("daily", budget.daily_usd, spend.daily),
+        ("monthly", budget.monthly_usd, spend.monthly),
+    ]
+}
+
+fn exhausted(scope: &str, budget: &Budget, spend: Spend) -> Option<String> {
+    periods(budget, spend)
+        .into_iter()
+        .find_map(|(period, limit, spent)| {
+            let limit = limit?;
+            (spent >= limit).then(|| {
+                format!(
+                    "The {} budget of ${:.2} for {} is exhausted (${:.2} spent)",
+                    period, limit, scope, spent
+                )
+            })
+        })
+}
+
+/// Log once for every threshold crossed by going from `before` to `after`.
+fn warn_crossings(scope: &str, budget: &Budget, before: Spend, after: Spend) {
+    for ((period, limit, prev), (_, _, now)) in periods(budget, before)
+        .into_iter()
+        .zip(periods(budget, after))
+    {
+        let Some(limit) = limit else { continue };
+        for &threshold in budget.warn_at.iter().chain(&[1.0]) {
+            let mark = limit * threshold;
+            if prev < mark && now >= mark {
+                eprintln!(
+                    "[budget] {} has spent ${:.2} of its ${:.2} {} budget ({:.0}%)",
+                    scope,
+                    now,
+                    limit,
+                    period,
+                    now / limit * 100.0
+                );
+            }
+        }
+    }
+}
+
+/// Running spend for the proxy, seeded from the usage store.
+#[derive(Debug, Default)]
+pub struct BudgetLedger {
+    inner: Mutex<Ledger>,
+}
+
+impl BudgetLedger {
+    pub fn load(config: &ProfileConfig) -> Self {
+        let ledger = Self::default();
+        ledger.rebuild(config);
+        ledger
+    }
+
+    /// Re-read budgets and prices from `config` and recompute spend from the
+    /// usage store, so a price change applies to this month's usage too.
+    pub fn rebuild(&self, config: &ProfileConfig) {
+        let records = match usage::default_path().and_then(|path| usage::load(&path)) {
+            Ok(records) => records,
+            Err(e) => {
+                eprintln!("[budget] failed to read usage store: {:#}", e);
+                Vec::new()
+            }
+        };
+        *self.inner.lock().unwrap() = Ledger::new(config, &records, SystemTime::now());
+    }
+
+    /// Whether `config` changes any budget or price, so the ledger needs a
+    /// `rebuild`.
+    pub fn is_stale(&self, config: &ProfileConfig) -> bool {
+        !self.inner.lock().unwrap().matches(config)
+    }
+
+    pub fn check(&self, provider_name: &str) -> BudgetCheck {
+        let mut ledger = self.inner.lock().unwrap();
+        ledger.roll_over(SystemTime::now());
+        ledger.check(provider_name)
+    }
+
+    /// Add a newly recorded response's cost, warning on thresholds crossed.
+    pub fn record(&self, record: &UsageRecord) {
+        let mut ledger = self.inner.lock().unwrap();
+        ledger.roll_over(SystemTime::now());
+        let Some(cost) = record.cost(&ledger.prices) else {
+            return;
+        };
+
+        let before = ledger.spend(&record.provider);
+        ledger
+            .providers
+            .entry(record.provider.clone())
+            .or_default()
+            .add(cost);
+        if let Some(budget) = ledger.budgets.get(&record.provider) {
+            let scope = format!("provider '{}'", record.provider);
+            warn_crossings(&scope, budget, before, ledger.spend(&record.provider));
+        }
+
+        let before = ledger.total;
+        ledger.total.add(cost);
+        if let Some(budget) = &ledger.global {
+            warn_crossings("all providers", budget, before, ledger.total);
+        }
+    }
+
+    /// One line per configured budget, for `status`.
+    pub fn summary(&self) -> Vec<String> {
+        let ledger = self.inner.lock().unwrap();
+        let mut scopes: Vec<(String, &Budget, Spend)> = ledger
+            .global
+            .iter()
+            .map(|b| ("all providers".to_string(), b, ledger.total))
+            .collect();
+        let mut names: Vec<&String> = ledger.budgets.keys().collect();
+        names.sort();
+        for name in names {
+            scopes.push((name.clone(), &ledger.budgets[name], ledger.spend(name)));
+        }
+
+        scopes
+            .into_iter()
+            .map(|(scope, budget, spend)| {
+                let parts: Vec<String> = periods(budget, spend)
+                    .into_iter()
+                    .filter_map(|(period, limit, spent)| {
+                        let limit = limit?;
+                        Some(format!(
+                            "{} ${:.2} / ${:.2} ({:.0}%)",
+                            period,
+                            spent,
+                            limit,
+                            spent / limit * 100.0
+                        ))
+                    })
+                    .collect();
+                format!("Budget ({}): {}", scope, parts.join(", "))
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Provider;
+    use crate::time::parse_rfc3339;
+
+    fn record(day: &str, provider: &str, input: u64) -> UsageRecord {
+        UsageRecord {
+            timestamp: format!("{}T12:00:00.000Z", day),
+            day: day.to_string(),
+            provider: provider.to_string(),
+            model: "m".to_string(),
+            tier: None,
+            input_tokens: input,
+            output_tokens: 0,
+            cache_creation_input_tokens: 0,
+            cache_read_input_tokens: 0,
+        }
+    }
+
+    fn config() -> ProfileConfig {
+        let mut providers = HashMap::new();
+        providers.insert(
+            "glm".to_string(),
+            Provider {
+                budget: Some(Budget {
+                    daily_usd: Some(2.0),
+                    ..Default::default()
+                }),
+                ..Default::default()
+            },
+        );
+        providers.insert("cheap".to_string(), Provider::default());
+        ProfileConfig {
+            providers,
+            prices: HashMap::from([(
+                "m".to_string(),
+                ModelPrice {
+                    input: 1.0,
+                    output: 1.0,
+                    ..Default::default()
+                },
+            )]),
+            budget: Some(Budget {
+                monthly_usd: Some(10.0),
+                reroute_to: Some("cheap".to_string()),
+                ..Default::default()
+            }),
+            ..Default::default()
+        }
+    }
+
+    fn ledger_at(records: &[UsageRecord]) -> Ledger {
+        let now = parse_rfc3339("2026-03-15T18:00:00Z").unwrap();
+        Ledger::new(&config(), records, now)
+    }
+
+    #[test]
+    fn spend_is_seeded_from_current_day_and_month() {
+        let ledger = ledger_at(&[
+            record("2026-03-15", "glm", 1_000_000),
+            record("2026-03-02", "glm", 3_000_000),
+            record("2026-02-28", "glm", 9_000_000),
+        ]);
+        assert_eq!(
+            ledger.spend("glm"),
+            Spend {
+                daily: 1.0,
+                monthly: 4.0
+            }
+        );
+        assert_eq!(ledger.check("glm"), BudgetCheck::Allow);
+    }
+
+    #[test]
+    fn provider_budget_rejects_without_reroute() {
+        let ledger = ledger_at(&[record("2026-03-15", "glm", 2_000_000)]);
+        match ledger.check("glm") {
+            BudgetCheck::Exceeded {
+                message,
+                reroute_to,
+            } => {
+                assert!(message.contains("daily budget of $2.00 for provider 'glm'"));
+                assert_eq!(reroute_to, None);
+            }
+            BudgetCheck::Allow => panic!("expected budget to be exhausted"),
+        }
+    }
+
+    #[test]
+    fn global_budget_reroutes_but_spares_target() {
+        let ledger = ledger_at(&[record("2026-03-01", "other", 10_000_000)]);
+        assert!(matches!(
+            ledger.check("other"),
+            BudgetCheck::Exceeded { reroute_to: Some(ref target), .. } if target == "cheap"
+        ));
+        assert_eq!(ledger.check("cheap"), BudgetCheck::Allow);
+    }
+
+    #[test]
+    fn only_budget_and_price_changes_need_a_rebuild() {
+        let ledger = ledger_at(&[]);
+        let mut switched = config();
+        switched.active = "cheap".to_string();
+        switched.providers.get_mut("cheap").unwrap().base_url = "http://x".to_string();
+        assert!(ledger.matches(&switched));
+        switched.providers.get_mut("cheap").unwrap().budget = Some(Budget::default());
+        assert!(!ledger.matches(&switched));
+
+        let mut repriced = config();
+        repriced.prices.get_mut("m").unwrap().output = 2.0;
+        assert!(!ledger.matches(&repriced));
+    }
+
+    #[test]
+    fn roll_over_resets_daily_then_monthly() {
+        let mut ledger = ledger_at(&[record("2026-03-15", "glm", 1_000_000)]);
+        ledger.roll_over(parse_rfc3339("2026-03-16T00:00:01Z").unwrap());
+        assert_eq!(ledger.spend("glm").daily, 0.0);
+        assert_eq!(ledger.spend("glm").monthly, 1.0);
+        ledger.roll_over(parse_rfc3339("2026-04-01T00:00:01Z").unwrap());
+        assert_eq!(ledger.total, Spend::default());
+    }
+}
@@ -0,0 +1,512 @@
+//! Adapting Messages requests to what a provider supports, per its
+//! `capabilities` block. Whatever cannot be adapted becomes a 400.
+
+use anyhow::Result;
+use serde_json::{json, Value};
+
+use crate::config::{Capabilities, TokenCounting};
+use crate::openai::sse_event;
+use crate::proxy::ProxyError;
+use crate::tokens;
+
+/// The smallest thinking budget Anthropic-style APIs accept.
+const MIN_THINKING_BUDGET: u64 = 1_024;
+
+fn content_blocks(body: &mut Value) -> impl Iterator<Item = &mut Vec<Value>> {
+    body.get_mut("messages")
+        .and_then(Value::as_array_mut)
+        .into_iter()
+        .flatten()
+        .filter_map(|message| message.get_mut("content")?.as_array_mut())
+}
+
+fn block_type(block: &Value) -> &str {
+    block
+        .get("type")
+        .and_then(Value::as_str)
+        .unwrap_or_default()
+}
+
+fn remove_cache_control(value: &mut Value) -> usize {
+    match value {
+        Value::Object(map) => {
+            usize::from(map.remove("cache_control").is_some())
+                + map.values_mut().map(remove_cache_control).sum::<usize>()
+        }
+        Value::Array(items) => items.iter_mut().map(remove_cache_control).sum(),
+        _ => 0,
+    }
+}
+
+fn replace_images(blocks: &mut [Value]) -> usize {
+    let mut replaced = 0;
+    for block in blocks.iter_mut() {
+        if block_type(block) == "image" {
+            *block =
+                json!({"type": "text", "text": "[image omitted: not supported by this provider]"});
+            replaced += 1;
+        } else if let Some(inner) = block.get_mut("content").and_then(Value::as_array_mut) {
+            replaced += replace_images(inner);
+        }
+    }
+    replaced
+}
+
+/// Tool calls and results from earlier turns, as plain text.
+fn tool_block_as_text(block: &Value) -> Option<Value> {
+    let text = match block_type(block) {
+        "tool_use" => format!(
+            "[tool call {}: {}]",
+            block
+                .get("name")
+                .and_then(Value::as_str)
+                .unwrap_or_default(),
+            block.get("input").unwrap_or(&Value::Null)
+        ),
+        "tool_result" => {
+            let content = match block.get("content") {
+                Some(Value::String(s)) => s.clone(),
+                Some(Value::Array(parts)) => parts
+                    .iter()
+                    .filter_map(|p| p.get("text").and_then(Value::as_str))
+                    .collect::<Vec<_>>()
+                    .join("\n"),
+                _ => String::new(),
+            };
+            format!("[tool result: {}]", content)
+        }
+        _ => return None,
+    };
+    Some(json!({"type": "text", "text": text}))
+}
+
+/// Adapt `body` to `caps` for `provider`'s `model`, sizing the prompt with
+/// `counting`. Returns a description of each change for the log, or an
+/// `invalid_request_error` when the request cannot be served at all.
+pub fn sanitize(
+    caps: &Capabilities,
+    counting: &TokenCounting,
+    provider: &str,
+    model: Option<&str>,
+    body: &mut Value,
+) -> Result<Vec<String>> {
+    let mut changes = Vec::new();
+    let reject = |message: String| Err(ProxyError::invalid_request(message).into());
+
+    // The response is replayed as a stream; see `message_events`.
+    if !caps.streaming && body.get("stream").and_then(Value::as_bool) == Some(true) {
+        body["stream"] = json!(false);
+        changes.push("requested a non-streaming response".to_string());
+    }
+
+    if !caps.tools {
+        let forced = body
+            .pointer("/tool_choice/type")
+            .and_then(Value::as_str)
+            .is_some_and(|t| t == "any" || t == "tool");
+        if forced {
+            return reject(format!(
+                "{} does not support tools, but tool_choice requires one",
+                provider
+            ));
+        }
+        if let Some(map) = body.as_object_mut() {
+            if map.remove("tools").is_some() | map.remove("tool_choice").is_some() {
+                changes.push("dropped tools".to_string());
+            }
+        }
+        let mut converted = 0;
+        for blocks in content_blocks(body) {
+            for block in blocks.iter_mut() {
+                if let Some(text) = tool_block_as_text(block) {
+                    *block = text;
+                    converted += 1;
+                }
+            }
+        }
+        if converted > 0 {
+            changes.push(format!("turned {} tool blocks into text", converted));
+        }
+    }
+
+    if !caps.thinking {
+        if body
+            .as_object_mut()
+            .and_then(|m| m.remove("thinking"))
+            .is_some()
+        {
+            changes.push("removed thinking".to_string());
+        }
+        let mut removed = 0;
+        for blocks in content_blocks(body) {
+            let before = blocks.len();
+            blocks.retain(|b| !matches!(block_type(b), "thinking" | "redacted_thinking"));
+            removed += before - blocks.len();
+        }
+        if removed > 0 {
+            changes.push(format!("removed {} thinking blocks", removed));
+        }
+    }
+
+    if !caps.vision {
+        let replaced: usize = content_blocks(body).map(|b| replace_images(b)).sum();
+        if replaced > 0 {
+            changes.push(format!("replaced {} images with text", replaced));
+        }
+    }
+
+    if !caps.prompt_caching {
+        let removed = remove_cache_control(body);
+        if removed > 0 {
+            changes.push(format!("dropped {} cache_control markers", removed));
+        }
+    }
+
+    let limits = caps.limits(model);
+    let requested = body.get("max_tokens").and_then(Value::as_u64);
+    let mut max_tokens = requested;
+    if let (Some(tokens), Some(limit)) = (max_tokens, limits.max_output_tokens) {
+        max_tokens = Some(tokens.min(limit));
+    }
+    if let Some(context) = limits.max_context_tokens {
+        // Claude Code compacts the conversation when it sees this message.
+        let input = tokens::count_input_tokens(body, counting);
+        if input >= context {
+            return reject(format!(
+                "prompt is too long: {} tokens > {} maximum",
+                input, context
+            ));
+        }
+        max_tokens = max_tokens.map(|tokens| tokens.min(context - input));
+    }
+    if let (Some(from), Some(to)) = (requested, max_tokens) {
+        if to < from {
+            body["max_tokens"] = json!(to);
+            changes.push(format!("clamped max_tokens {} -> {}", from, to));
+        }
+    }
+
+    // The thinking budget must stay below max_tokens.
+    if let (Some(max_tokens), Some(budget)) = (
+        max_tokens,
+        body.pointer("/thinking/budget_tokens")
+            .and_then(Value::as_u64),
+    ) {
+        if budget >= max_tokens {
+            if max_tokens > MIN_THINKING_BUDGET {
+                body["thinking"]["budget_tokens"] = json!(max_tokens - 1);
+                changes.push(format!(
+                    "clamped thinking budget {} -> {}",
+                    budget,
+                    max_tokens - 1
+                ));
+            } else if let Some(map) = body.as_object_mut() {
+                map.remove("thinking");
+                changes
+                    .push("removed thinking, max_tokens is below its minimum budget".to_string());
+            }
+        }
+    }
+    Ok(changes)
+}
+
+/// A complete Messages response as the event stream a streaming request
+/// would have received, for providers that cannot stream. `None` when the
+/// body is not a message object.
+pub fn message_events(message: &Value) -> Option<Vec<u8>> {
+    if !message.is_object() {
+        return None;
+    }
+    let mut out = Vec::new();
+    let usage = message
+        .get("usage")
+        .filter(|u| u.is_object())
+        .cloned()
+        .unwrap_or_else(|| json!({}));
+    let mut start = message.clone();
+    start["content"] = json!([]);
+    start["stop_reason"] = Value::Null;
+    start["stop_sequence"] = Value::Null;
+    start["usage"] = usage.clone();
+    start["usage"]["output_tokens"] = json!(0);
+    sse_event(
+        &mut out,
+        "message_start",
+        &json!({"type": "message_start", "message": start}),
+    );
+
+    let blocks = message.get("content").and_then(Value::as_array);
+    for (index, block) in blocks.into_iter().flatten().enumerate() {
+        let (skeleton, delta) = match block_type(block) {
+            "text" => (
+                json!({"type": "text", "text": ""}),
+                Some(json!({"type": "text_delta", "text": block["text"]})),
+            ),
+            "tool_use" => {
+                let mut skeleton = block.clone();
+                skeleton["input"] = json!({});
+                let input = block.get("input").unwrap_or(&Value::Null).to_string();
+                (
+                    skeleton,
+                    Some(json!({"type": "input_json_delta", "partial_json": input})),
+                )
+            }
+            "thinking" => (
+                json!({"type": "thinking", "thinking": "", "signature": ""}),
+                Some(json!({"type": "thinking_delta", "thinking": block["thinking"]})),
+            ),
+            _ => (block.clone(), None),
+        };
+        sse_event(
+            &mut out,
+            "content_block_start",
+            &json!({"type": "content_block_start", "index": index, "content_block": skeleton}),
+        );
+        if let Some(delta) = delta {
+            sse_event(
+                &mut out,
+                "content_block_delta",
+                &json!({"type": "content_block_delta", "index": index, "delta": delta}),
+            );
+        }
+        if let Some(signature) = block
+            .get("signature")
+            .filter(|_| block_type(block) == "thinking")
+        {
+            sse_event(
+                &mut out,
+                "content_block_delta",
+                &json!({
+                    "type": "content_block_delta",
+                    "index": index,
+                    "delta": {"type": "signature_delta", "signature": signature},
+                }),
+            );
+        }
+        sse_event(
+            &mut out,
+            "content_block_stop",
+            &json!({"type": "content_block_stop", "index": index}),
+        );
+    }
+
+    sse_event(
+        &mut out,
+        "message_delta",
+        &json!({
+            "type": "message_delta",
+            "delta": {
+                "stop_reason": message.get("stop_reason").cloned().unwrap_or(Value::Null),
+                "stop_sequence": message.get("stop_sequence").cloned().unwrap_or(Value::Null),
+            },
+            "usage": {"output_tokens": usage.get("output_tokens").cloned().unwrap_or(json!(0))},
+        }),
+    );
+    sse_event(&mut out, "message_stop", &json!({"type": "message_stop"}));
+    Some(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request() -> Value {
+        json!({
+            "model": "glm-4.7",
+            "max_tokens": 32000,
+            "stream": true,
+            "thinking": {"type": "enabled", "budget_tokens": 16000},
+            "system": [{"type": "text", "text": "be brief", "cache_control": {"type": "ephemeral"}}],
+            "tools": [{"name": "read", "input_schema": {}}],
+            "messages": [
+                {"role": "user", "content": [
+                    {"type": "image", "source": {"type": "base64", "media_type": "image/png", "data": "A".repeat(40_000)}},
+                    {"type": "text", "text": "what is this?"}
+                ]},
+                {"role": "assistant", "content": [
+                    {"type": "thinking", "thinking": "hmm", "signature": "s"},
+                    {"type": "tool_use", "id": "t1", "name": "read", "input": {"path": "a"}}
+                ]},
+                {"role": "user", "content": [
+                    {"type": "tool_result", "tool_use_id": "t1", "content": "file body"}
+                ]}
+            ]
+        })
+    }
+
+    #[test]
+    fn unsupported_features_are_adapted() {
+        let caps = Capabilities {
+            tools: false,
+            vision: false,
+            thinking: false,
+            prompt_caching: false,
+            max_output_tokens: Some(8192),
+            ..Default::default()
+        };
+        let mut body = request();
+        let changes = sanitize(
+            &caps,
+            &TokenCounting::default(),
+            "glm",
+            Some("glm-4.7"),
+            &mut body,
+        )
+        .unwrap();
+        assert_eq!(
+            changes,
+            [
+                "dropped tools",
+                "turned 2 tool blocks into text",
+                "removed thinking",
+                "removed 1 thinking blocks",
+                "replaced 1 images with text",
+                "dropped 1 cache_control markers",
+                "clamped max_tokens 32000 -> 8192",
+            ]
+        );
+        assert!(body.get("tools").is_none() && body.get("thinking").is_none());
+        assert_eq!(body["max_tokens"], 8192);
+        assert_eq!(
+            body["messages"][1]["content"][0]["text"],
+            "[tool call read: {\"path\":\"a\"}]"
+        );
+        assert_eq!(
+            body["messages"][2]["content"][0]["text"],
+            "[tool result: file body]"
+        );
+        assert!(body["system"][0].get("cache_control").is_none());
+    }
+
+    #[test]
+    fn limits_clamp_output_and_thinking() {
+        let caps = Capabilities {
+            max_output_tokens: Some(64_000),
+            models: [(
+                "glm-4.7".to_string(),
+                crate::config::ModelLimits {
+                    max_output_tokens: Some(10_000),
+                    ..Default::default()
+                },
+            )]
+            .into(),
+            ..Default::default()
+        };
+        let mut body = request();
+        sanitize(
+            &caps,
+            &TokenCounting::default(),
+            "glm",
+            Some("glm-4.7"),
+            &mut body,
+        )
+        .unwrap();
+        assert_eq!(body["max_tokens"], 10_000);
+        assert_eq!(body["thinking"]["budget_tokens"], 9_999);
+
+        let mut body = request();
+        sanitize(
+            &caps,
+            &TokenCounting::default(),
+            "glm",
+            Some("other"),
+            &mut body,
+        )
+        .unwrap();
+        assert_eq!(body["max_tokens"], 32_000);
+    }
+
+    #[test]
+    fn unservable_requests_are_rejected() {
+        let forced_tool = Capabilities {
+            tools: false,
+            ..Default::default()
+        };
+        let mut body = request();
+        body["tool_choice"] = json!({"type": "any"});
+        let err = sanitize(
+            &forced_tool,
+            &TokenCounting::default(),
+            "glm",
+            None,
+            &mut body,
+        )
+        .unwrap_err();
+        let err = err.downcast_ref::<ProxyError>().unwrap();
+        assert_eq!((err.status, err.error_type), (400, "invalid_request_error"));
+
+        let tiny_context = Capabilities {
+            max_context_tokens: Some(1_000),
+            ..Default::default()
+        };
+        let err = sanitize(
+            &tiny_context,
+            &TokenCounting::default(),
+            "glm",
+            None,
+            &mut request(),
+        )
+        .unwrap_err();
+        assert!(err.to_string().starts_with("prompt is too long"));
+    }
+
+    #[test]
+    fn streaming_is_emulated() {
+        let no_streaming = Capabilities {
+            streaming: false,
+            ..Default::default()
+        };
+        let mut body = request();
+        let changes = sanitize(
+            &no_streaming,
+            &TokenCounting::default(),
+            "glm",
+            None,
+            &mut body,
+        )
+        .unwrap();
+        assert_eq!(changes, ["requested a non-streaming response"]);
+        assert_eq!(body["stream"], false);
+
+        let message = json!({
+            "id": "msg_1",
+            "type": "message",
+            "role": "assistant",
+            "model": "glm-4.7",
+            "content": [
+                {"type": "text", "text": "Reading it."},
+                {"type": "tool_use", "id": "t1", "name": "read", "input": {"path": "a"}}
+            ],
+            "stop_reason": "tool_use",
+            "stop_sequence": null,
+            "usage": {"input_tokens": 12, "output_tokens": 7}
+        });
+        let events = String::from_utf8(message_events(&message).unwrap()).unwrap();
+        let names: Vec<&str> = events
+            .lines()
+            .filter_map(|line| line.strip_prefix("event: "))
+            .collect();
+        assert_eq!(
+            names,
+            [
+                "message_start",
+                "content_block_start",
+                "content_block_delta",
+                "content_block_stop",
+                "content_block_start",
+                "content_block_delta",
+                "content_block_stop",
+                "message_delta",
+                "message_stop",
+            ]
+        );
+        assert!(events.contains(r#""partial_json":"{\"path\":\"a\"}""#));
+        assert!(events.contains(r#""usage":{"output_tokens":7}"#));
+        assert!(events.contains(r#""stop_reason":"tool_use""#));
+
+        // Bodies that are not message objects are not replayed.
+        assert!(message_events(&json!("overloaded")).is_none());
+        assert!(message_events(&json!([1, 2])).is_none());
+        assert!(message_events(&json!({"usage": 3})).is_some());
+    }
+}
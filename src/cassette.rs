@@ -0,0 +1,293 @@
+//! Record and replay of upstream exchanges, for offline and deterministic
+//! runs. Cassettes are keyed on the request as it leaves the proxy, after
+//! routing, model rewriting and translation.
+
+use anyhow::{Context, Result};
+use bytes::Bytes;
+use futures_util::StreamExt;
+use reqwest::Method;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::path::{Path, PathBuf};
+
+use crate::proxy::ProxyError;
+
+#[derive(Debug, Clone)]
+pub enum CassetteMode {
+    /// Forward upstream and save every exchange into the directory.
+    Record(PathBuf),
+    /// Serve exchanges from the directory without touching the network.
+    Replay(PathBuf),
+}
+
+/// Top-level request fields that change between otherwise identical runs,
+/// such as Claude Code's per-session `metadata.user_id`.
+const VOLATILE_FIELDS: [&str; 2] = ["metadata", "user"];
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RecordedRequest {
+    pub method: String,
+    pub url: String,
+    pub body: Value,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RecordedResponse {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    /// The full body; streamed responses are replayed one SSE event per chunk.
+    pub body: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Cassette {
+    pub request: RecordedRequest,
+    pub response: RecordedResponse,
+}
+
+impl RecordedRequest {
+    /// The request with volatile fields dropped. JSON bodies are compared as
+    /// values, so key order and whitespace do not matter.
+    pub fn normalized(method: &Method, url: &str, body: &[u8]) -> Self {
+        let body = match serde_json::from_slice::<Value>(body) {
+            Ok(Value::Object(mut map)) => {
+                for field in VOLATILE_FIELDS {
+                    map.remove(field);
+                }
+                Value::Object(map)
+            }
+            Ok(other) => other,
+            Err(_) if body.is_empty() => Value::Null,
+            Err(_) => Value::String(String::from_utf8_lossy(body).into_owned()),
+        };
+        Self {
+            method: method.to_string(),
+            url: url.to_string(),
+            body,
+        }
+    }
+
+    /// Stable across runs and Rust versions: FNV-1a over the canonical JSON.
+    pub fn key(&self) -> String {
+        let canonical = serde_json::to_vec(self).unwrap_or_default();
+        let hash = canonical.iter().fold(0xcbf2_9ce4_8422_2325u64, |hash, &b| {
+            (hash ^ u64::from(b)).wrapping_mul(0x0100_0000_01b3)
+        });
+        format!("{:016x}", hash)
+    }
+}
+
+fn cassette_path(dir: &Path, key: &str) -> PathBuf {
+    dir.join(format!("{}.json", key))
+}
+
+fn build_response(
+    status: u16,
+    headers: &[(String, String)],
+    body: reqwest::Body,
+) -> Result<reqwest::Response> {
+    let mut builder = hyper::Response::builder().status(status);
+    for (name, value) in headers {
+        builder = builder.header(name.as_str(), value.as_str());
+    }
+    Ok(reqwest::Response::from(builder.body(body)?))
+}
+
+/// Split an SSE body after each blank line so events reach the client one at
+/// a time, as they did when recorded.
+fn sse_chunks(body: &str) -> Vec<Bytes> {
+    let mut chunks = Vec::new();
+    let mut rest = body;
+    while let Some(pos) = rest.find("\n\n") {
+        let (event, tail) = rest.split_at(pos + 2);
+        chunks.push(Bytes::copy_from_slice(event.as_bytes()));
+        rest = tail;
+    }
+    if !rest.is_empty() {
+        chunks.push(Bytes::copy_from_slice(rest.as_bytes()));
+    }
+    chunks
+}
+
+/// Serve a recorded exchange, or fail with a 404 naming the missing key.
+pub fn replay(dir: &Path, request: &RecordedRequest) -> Result<reqwest::Response> {
+    let key = request.key();
+    let path = cassette_path(dir, &key);
+    let cassette: Cassette = match std::fs::read_to_string(&path) {
+        Ok(content) => serde_json::from_str(&content)
+            .with_context(|| format!("Invalid cassette {}", path.display()))?,
+        Err(_) => {
+            return Err(ProxyError {
+                status: 404,
+                error_type: "not_found_error",
+                message: format!(
+                    "No cassette for {} {} (key {}) in {}",
+                    request.method,
+                    request.url,
+                    key,
+                    dir.display()
+                ),
+            }
+            .into())
+        }
+    };
+    eprintln!(
+        "[replay] {} {} <- {}",
+        request.method,
+        request.url,
+        path.display()
+    );
+
+    let response = &cassette.response;
+    let event_stream = response.headers.iter().any(|(name, value)| {
+        name.eq_ignore_ascii_case("content-type") && value.starts_with("text/event-stream")
+    });
+    let body = if event_stream {
+        let chunks = sse_chunks(&response.body)
+            .into_iter()
+            .map(Ok::<_, std::io::Error>);
+        reqwest::Body::wrap_stream(futures_util::stream::iter(chunks))
+    } else {
+        reqwest::Body::from(response.body.clone())
+    };
+    build_response(response.status, &response.headers, body)
+}
+
+/// Pass `response` through unchanged while copying its body, and write the
+/// cassette once the body has been read to the end. Exchanges cut short by
+/// an error or a client disconnect are not saved.
+pub fn record(
+    dir: &Path,
+    request: RecordedRequest,
+    response: reqwest::Response,
+) -> Result<reqwest::Response> {
+    std::fs::create_dir_all(dir)
+        .with_context(|| format!("Failed to create cassette directory {}", dir.display()))?;
+    let status = response.status().as_u16();
+    let headers: Vec<(String, String)> = response
+        .headers()
+        .iter()
+        .filter(|(name, _)| {
+            !matches!(
+                name.as_str(),
+                "content-length" | "transfer-encoding" | "connection" | "keep-alive"
+            )
+        })
+        .filter_map(|(name, value)| Some((name.to_string(), value.to_str().ok()?.to_string())))
+        .collect();
+    let path = cassette_path(dir, &request.key());
+
+    let pending = Some((Vec::new(), request, headers.clone()));
+    let stream = futures_util::stream::unfold(
+        (response.bytes_stream(), pending),
+        move |(mut upstream, mut pending)| {
+            let path = path.clone();
+            async move {
+                match upstream.next().await {
+                    Some(Ok(chunk)) => {
+                        if let Some((body, _, _)) = &mut pending {
+                            body.extend_from_slice(&chunk);
+                        }
+                        Some((Ok(chunk), (upstream, pending)))
+                    }
+                    Some(Err(e)) => Some((Err(e), (upstream, None))),
+                    None => {
+                        if let Some((body, request, headers)) = pending {
+                            let cassette = Cassette {
+                                request,
+                                response: RecordedResponse {
+                                    status,
+                                    headers,
+                                    body: String::from_utf8_lossy(&body).into_owned(),
+                                },
+                            };
+                            match serde_json::to_vec_pretty(&cassette)
+                                .map_err(anyhow::Error::from)
+                                .and_then(|json| Ok(std::fs::write(&path, json)?))
+                            {
+                                Ok(()) => eprintln!("[record] saved {}", path.display()),
+                                Err(e) => {
+                                    eprintln!("[record] failed to save {}: {:#}", path.display(), e)
+                                }
+                            }
+                        }
+                        None
+                    }
+                }
+            }
+        },
+    );
+    build_response(status, &headers, reqwest::Body::wrap_stream(stream))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("cms-cassette-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        dir
+    }
+
+    #[test]
+    fn key_ignores_volatile_fields_and_key_order() {
+        let url = "https://api.anthropic.com/v1/messages";
+        let a = RecordedRequest::normalized(
+            &Method::POST,
+            url,
+            br#"{"model":"m","max_tokens":5,"metadata":{"user_id":"session-1"}}"#,
+        );
+        let b = RecordedRequest::normalized(
+            &Method::POST,
+            url,
+            br#"{ "max_tokens": 5, "model": "m", "metadata": {"user_id": "session-2"} }"#,
+        );
+        assert_eq!(a.key(), b.key());
+        let c = RecordedRequest::normalized(&Method::POST, url, br#"{"model":"other"}"#);
+        assert_ne!(a.key(), c.key());
+    }
+
+    #[test]
+    fn sse_bodies_split_per_event() {
+        let chunks = sse_chunks("event: a\ndata: 1\n\nevent: b\ndata: 2\n\n");
+        assert_eq!(chunks.len(), 2);
+        assert_eq!(chunks[1], "event: b\ndata: 2\n\n");
+    }
+
+    #[tokio::test]
+    async fn recorded_exchange_replays() {
+        let dir = temp_dir("roundtrip");
+        let request = RecordedRequest::normalized(&Method::POST, "http://up/v1/messages", b"{}");
+        let upstream = build_response(
+            200,
+            &[("content-type".to_string(), "text/event-stream".to_string())],
+            reqwest::Body::from("data: 1\n\ndata: 2\n\n"),
+        )
+        .unwrap();
+
+        let recorded = record(&dir, request.clone(), upstream).unwrap();
+        assert_eq!(recorded.text().await.unwrap(), "data: 1\n\ndata: 2\n\n");
+
+        let replayed = replay(&dir, &request).unwrap();
+        assert_eq!(replayed.status(), 200);
+        let chunks: Vec<Bytes> = replayed
+            .bytes_stream()
+            .map(|chunk| chunk.unwrap())
+            .collect()
+            .await;
+        assert_eq!(chunks, ["data: 1\n\n", "data: 2\n\n"]);
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn replay_miss_is_a_404() {
+        let dir = temp_dir("miss");
+        let request = RecordedRequest::normalized(&Method::GET, "http://up/v1/models", b"");
+        let err = replay(&dir, &request).unwrap_err();
+        let err = err.downcast_ref::<ProxyError>().unwrap();
+        assert_eq!(err.status, 404);
+        assert!(err.message.contains(&request.key()));
+    }
+}
@@ -1,28 +1,11 @@
-use crate::config::{ModelMapping, ProfileConfig, Provider};
-use anyhow::{bail, Context, Result};
+use crate::admin::AdminClient;
+use crate::budget::BudgetLedger;
+use crate::config::{builtin_preset, ApiFormat, ModelMapping, ProfileConfig, Provider};
+use crate::daemon;
+use crate::health::{self, ProxyHealth};
+use anyhow::{anyhow, bail, Context, Result};
 use std::path::PathBuf;
 
-#[derive(Clone, Copy)]
-struct BuiltinProviderPreset {
-    base_url: &'static str,
-}
-
-fn builtin_provider_preset(name: &str) -> Option<BuiltinProviderPreset> {
-    let lower = name.to_ascii_lowercase();
-    match lower.as_str() {
-        "glm" => Some(BuiltinProviderPreset {
-            base_url: "https://open.z.ai/api/paas/v4",
-        }),
-        "openrouter" => Some(BuiltinProviderPreset {
-            base_url: "https://openrouter.ai/api/v1",
-        }),
-        "minimax" => Some(BuiltinProviderPreset {
-            base_url: "https://api.minimax.io/anthropic/v1",
-        }),
-        _ => None,
-    }
-}
-
 fn parse_credential(credential: String) -> Result<(Option<String>, Option<String>)> {
     let lower = credential.to_ascii_lowercase();
     if lower.starts_with("bearer:") {
@@ -56,15 +39,73 @@ pub fn cmd_list(config: &ProfileConfig) -> Result<()> {
             Some(m) => format!("{} / {} / {}", m.haiku, m.sonnet, m.opus),
             None => "(passthrough)".to_string(),
         };
-        println!("  {}{} - {} [{}]", name, marker, provider.base_url, models);
+        let format = match provider.api_format {
+            ApiFormat::Anthropic => String::new(),
+            other => format!(" ({})", other),
+        };
+        println!(
+            "  {}{} - {}{} [{}]",
+            name, marker, provider.base_url, format, models
+        );
     }
     Ok(())
 }
 
-pub fn cmd_status(config: &ProfileConfig) -> Result<()> {
+/// Describe the proxy found via the PID file and its `/healthz`, returning
+/// whether it is verifiably this program and answering.
+fn proxy_health(url: &str) -> Result<(bool, String)> {
+    let pid_path = pid_file_path()?;
+    let pid = if pid_path.exists() {
+        std::fs::read_to_string(&pid_path)?
+            .trim()
+            .parse::<u32>()
+            .ok()
+    } else {
+        None
+    };
+    let line = match (pid, health::check(url)) {
+        (Some(pid), ProxyHealth::Healthy(info)) if pid != info.pid => (
+            true,
+            format!(
+                "running (PID {}) at {}; PID file points to {} and is stale",
+                info.pid, url, pid
+            ),
+        ),
+        (_, ProxyHealth::Healthy(info)) => (
+            true,
+            format!("running (PID {}, v{}) at {}", info.pid, info.version, url),
+        ),
+        (_, ProxyHealth::Foreign) => (
+            false,
+            format!("not running; {} is answered by another service", url),
+        ),
+        (Some(pid), ProxyHealth::Unreachable) if daemon::process_alive(pid) => (
+            false,
+            format!("PID {} is alive but nothing answers at {}", pid, url),
+        ),
+        (Some(pid), ProxyHealth::Unreachable) => {
+            (false, format!("not running (stale PID file for {})", pid))
+        }
+        (None, ProxyHealth::Unreachable) => (false, "not running".to_string()),
+    };
+    Ok(line)
+}
+
+pub fn cmd_status(config: &ProfileConfig, check: bool) -> Result<()> {
+    let url = health::proxy_url();
+    if check {
+        let (healthy, line) = proxy_health(&url)?;
+        if !healthy {
+            bail!("Proxy: {}", line);
+        }
+        println!("Proxy: {}", line);
+        return Ok(());
+    }
+
     let provider = config.active_provider()?;
     println!("Active provider: {}", config.active);
     println!("Base URL: {}", provider.base_url);
+    println!("API format: {}", provider.api_format);
     match &provider.models {
         Some(m) => {
             println!("Haiku  -> {}", m.haiku);
@@ -73,22 +114,72 @@ pub fn cmd_status(config: &ProfileConfig) -> Result<()> {
         }
         None => println!("Models: passthrough (no rewriting)"),
     }
-    let pid_path = pid_file_path()?;
-    if pid_path.exists() {
-        let pid = std::fs::read_to_string(&pid_path)?;
-        println!("Proxy: running (PID {})", pid.trim());
-    } else {
-        println!("Proxy: not running");
+    if !provider.fallback.is_empty() {
+        println!("Fallback: {}", provider.fallback.join(" -> "));
+    }
+    for line in BudgetLedger::load(config).summary() {
+        println!("{}", line);
+    }
+
+    let (healthy, line) = proxy_health(&url)?;
+    if !healthy {
+        println!("Proxy: {}", line);
+        return Ok(());
+    }
+    match AdminClient::discover().and_then(|client| client.in_flight().ok().flatten()) {
+        Some(n) => println!("Proxy: {}, {} in flight", line, n),
+        None => println!("Proxy: {}", line),
+    }
+    if let Some(log) = log_file_path().ok().filter(|path| path.exists()) {
+        println!("Log: {}", log.display());
+    }
+    match health::readiness(&url) {
+        Some((true, _)) => println!("Readiness: ready"),
+        Some((false, report)) => {
+            println!("Readiness: not ready");
+            for error in report["config"]["errors"].as_array().into_iter().flatten() {
+                println!("  config: {}", error.as_str().unwrap_or_default());
+            }
+            if let Some(error) = report["config"]["last_reload_error"].as_str() {
+                println!("  last reload failed: {}", error);
+            }
+            for name in health::unreachable_providers(&report) {
+                let error = report["providers"][&name]["error"]
+                    .as_str()
+                    .unwrap_or("unreachable");
+                println!("  {} unreachable: {}", name, error);
+            }
+        }
+        None => println!("Readiness: unknown (no answer from /readyz)"),
     }
     Ok(())
 }
 
 pub fn cmd_use(config: &mut ProfileConfig, provider: &str) -> Result<()> {
-    if !config.providers.contains_key(provider) {
-        bail!(
+    let unknown = || {
+        anyhow!(
             "Unknown provider '{}'. Run 'claude-model-switch list' to see available providers.\nTo add a new provider: claude-model-switch add {} <base-url> <api-key>\nOr for built-in presets: claude-model-switch add {} <api-key>",
             provider, provider, provider
-        );
+        )
+    };
+
+    // A running proxy switches and saves the config itself, and tells us
+    // whether it worked.
+    if let Some(client) = AdminClient::discover() {
+        match client.set_active(provider) {
+            Ok(Some(())) => {
+                println!("Switched to: {}", provider);
+                println!("Proxy is now routing to '{}'.", provider);
+                return Ok(());
+            }
+            Ok(None) => {}
+            Err(_) if !config.providers.contains_key(provider) => return Err(unknown()),
+            Err(e) => return Err(e),
+        }
+    }
+
+    if !config.providers.contains_key(provider) {
+        return Err(unknown());
     }
     config.active = provider.to_string();
     config.save()?;
@@ -110,6 +201,17 @@ pub fn cmd_use(config: &mut ProfileConfig, provider: &str) -> Result<()> {
     Ok(())
 }
 
+/// Ask a running proxy to pick up a config change that was just saved.
+fn notify_proxy() {
+    if let Some(client) = AdminClient::discover() {
+        match client.reload() {
+            Ok(Some(())) => println!("Proxy reloaded configuration."),
+            Ok(None) => {}
+            Err(e) => eprintln!("Warning: {:#}", e),
+        }
+    }
+}
+
 pub fn cmd_setup(
     config: &mut ProfileConfig,
     provider: &str,
@@ -132,11 +234,14 @@ pub fn cmd_setup(
             provider, provider, provider
         );
     }
+    config.migrate_auth();
     config.save()?;
     println!("Credentials saved for '{}'.", provider);
+    notify_proxy();
     Ok(())
 }
 
+#[allow(clippy::too_many_arguments)]
 pub fn cmd_add(
     config: &mut ProfileConfig,
     name: &str,
@@ -148,6 +253,7 @@ pub fn cmd_add(
     opus: Option<&str>,
     api_key: Option<String>,
     auth_token: Option<String>,
+    api_format: Option<ApiFormat>,
 ) -> Result<()> {
     let mut base_url_reused_from_existing = false;
     let mut base_url_from_preset: Option<&'static str> = None;
@@ -184,14 +290,14 @@ pub fn cmd_add(
     };
 
     let existing = config.providers.get(name).cloned();
-    let preset = builtin_provider_preset(name);
+    let preset = builtin_preset(name);
     let resolved_base_url = match base_url.or(positional_base_url.as_deref()) {
         Some(url) => url.to_string(),
         None => {
             if let Some(existing_provider) = &existing {
                 base_url_reused_from_existing = true;
                 existing_provider.base_url.clone()
-            } else if let Some(preset) = preset {
+            } else if let Some(preset) = &preset {
                 base_url_from_preset = Some(preset.base_url);
                 preset.base_url.to_string()
             } else {
@@ -227,6 +333,15 @@ pub fn cmd_add(
         None => existing.as_ref().and_then(|p| p.auth_token.clone()),
     };
 
+    let resolved_api_format = match (api_format, existing.as_ref()) {
+        (Some(format), _) => format,
+        (None, Some(existing_provider)) => existing_provider.api_format,
+        (None, None) => match (base_url_from_preset, &preset) {
+            (Some(_), Some(preset)) => preset.api_format,
+            _ => ApiFormat::default(),
+        },
+    };
+
     let provider_existed = existing.is_some();
     let has_model_mapping = models.is_some();
 
@@ -237,8 +352,11 @@ pub fn cmd_add(
             api_key: resolved_api_key,
             auth_token: resolved_auth_token,
             models,
+            api_format: resolved_api_format,
+            ..existing.unwrap_or_default()
         },
     );
+    config.migrate_auth();
     config.save()?;
     if provider_existed {
         println!("Updated provider '{}'.", name);
@@ -257,6 +375,9 @@ pub fn cmd_add(
             preset_url
         );
     }
+    if resolved_api_format == ApiFormat::Openai {
+        println!("API format: openai (Messages requests translated to Chat Completions).");
+    }
     if has_model_mapping {
         println!("Model rewriting: enabled for Claude tiers (haiku/sonnet/opus).");
     } else {
@@ -275,6 +396,7 @@ pub fn cmd_add(
             name
         );
     }
+    notify_proxy();
     Ok(())
 }
 
@@ -285,12 +407,16 @@ pub fn cmd_remove(config: &mut ProfileConfig, name: &str) -> Result<()> {
     if config.providers.remove(name).is_none() {
         bail!("Provider '{}' not found.", name);
     }
+    for provider in config.providers.values_mut() {
+        provider.fallback.retain(|fallback| fallback != name);
+    }
     if config.active == name {
         config.active = "claude".to_string();
         println!("Active provider was '{}', switched back to 'claude'.", name);
     }
     config.save()?;
     println!("Removed provider '{}'.", name);
+    notify_proxy();
     Ok(())
 }
 
@@ -342,3 +468,9 @@ pub fn pid_file_path() -> Result<PathBuf> {
     let home = dirs::home_dir().context("Could not find home directory")?;
     Ok(home.join(".claude").join("model-switch-proxy.pid"))
 }
+
+/// Where a proxy started with `start` writes its output.
+pub fn log_file_path() -> Result<PathBuf> {
+    let home = dirs::home_dir().context("Could not find home directory")?;
+    Ok(home.join(".claude").join("model-switch-proxy.log"))
+}
@@ -1,42 +1,203 @@
-use crate::commands::pid_file_path;
+use crate::admin::{AdminClient, AdminEndpoint};
+use crate::cassette::CassetteMode;
+use crate::commands::{log_file_path, pid_file_path};
+use crate::config::ProfileConfig;
+use crate::health::{self, ProxyHealth};
 use anyhow::{bail, Context, Result};
 use std::process::Command;
+use std::time::{Duration, Instant};
 
-pub fn start_daemon(port: u16) -> Result<()> {
+/// How long `start` waits for the new proxy to answer `/healthz`.
+const STARTUP_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How much longer than the grace period `stop` waits for the proxy to exit.
+const STOP_MARGIN: Duration = Duration::from_secs(5);
+
+pub fn process_alive(pid: u32) -> bool {
+    // An exited process stays a zombie until its parent reaps it, which in
+    // containers without a proper init can take a while; it is not running.
+    if let Ok(stat) = std::fs::read_to_string(format!("/proc/{}/stat", pid)) {
+        let state = stat
+            .rsplit_once(") ")
+            .and_then(|(_, rest)| rest.chars().next());
+        if state == Some('Z') {
+            return false;
+        }
+    }
+    Command::new("kill")
+        .arg("-0")
+        .arg(pid.to_string())
+        .stderr(std::process::Stdio::null())
+        .status()
+        .map(|s| s.success())
+        .unwrap_or(false)
+}
+
+pub fn start_daemon(port: u16, cassettes: Option<&CassetteMode>) -> Result<()> {
     let pid_path = pid_file_path()?;
+    let url = format!("http://127.0.0.1:{}", port);
 
     if pid_path.exists() {
         let pid_str = std::fs::read_to_string(&pid_path)?;
         if let Ok(pid) = pid_str.trim().parse::<u32>() {
-            let status = Command::new("kill").arg("-0").arg(pid.to_string()).status();
-            if status.map(|s| s.success()).unwrap_or(false) {
-                bail!(
-                    "Proxy already running (PID {}). Stop it first with: claude-model-switch stop",
-                    pid
-                );
+            // Ask the proxy at its published address, which may be on
+            // another port than the one requested. A live PID is only
+            // treated as stale when something else answers there, since PIDs
+            // get reused after a crash.
+            if process_alive(pid) {
+                let running = health::proxy_url();
+                match health::check(&running) {
+                    ProxyHealth::Foreign => {}
+                    ProxyHealth::Healthy(info) if info.pid == pid => bail!(
+                        "Proxy already running (PID {}) at {}. Stop it first with: claude-model-switch stop",
+                        pid,
+                        running
+                    ),
+                    _ => bail!(
+                        "Proxy already running (PID {}). Stop it first with: claude-model-switch stop",
+                        pid
+                    ),
+                }
             }
-            std::fs::remove_file(&pid_path)?;
         }
+        std::fs::remove_file(&pid_path)?;
+    }
+
+    match health::check(&url) {
+        ProxyHealth::Healthy(info) => bail!(
+            "Another claude-model-switch proxy (PID {}) is already serving {}",
+            info.pid,
+            url
+        ),
+        ProxyHealth::Foreign => bail!(
+            "Port {} is in use by another service. Pick another with: claude-model-switch start --port <N>",
+            port
+        ),
+        ProxyHealth::Unreachable => {}
     }
 
     let exe = std::env::current_exe().context("Could not determine executable path")?;
 
-    let child = Command::new(&exe)
+    let mut command = Command::new(&exe);
+    command
         .arg("start")
         .arg("--port")
         .arg(port.to_string())
-        .arg("--foreground")
-        .stdout(std::process::Stdio::null())
-        .stderr(std::process::Stdio::null())
+        .arg("--foreground");
+    // The child keeps our working directory, but an absolute path reads
+    // better in `ps` and survives a later `cd` in scripts.
+    match cassettes {
+        Some(CassetteMode::Record(dir)) => {
+            command.arg("--record").arg(std::path::absolute(dir)?);
+        }
+        Some(CassetteMode::Replay(dir)) => {
+            command.arg("--replay").arg(std::path::absolute(dir)?);
+        }
+        None => {}
+    }
+    // Retries, fallbacks and request adaptations are logged to stderr. Each
+    // run starts a fresh log so it cannot grow without bound; the previous
+    // run's is kept alongside, for looking into a crash after a restart.
+    let log_path = log_file_path()?;
+    if log_path.exists() {
+        std::fs::rename(&log_path, log_path.with_extension("log.1"))
+            .with_context(|| format!("Failed to rotate {}", log_path.display()))?;
+    }
+    let log = std::fs::File::create(&log_path)
+        .with_context(|| format!("Failed to open {}", log_path.display()))?;
+    let mut child = command
+        .stdout(log.try_clone()?)
+        .stderr(log)
         .spawn()
         .context("Failed to spawn proxy process")?;
 
     let pid = child.id();
     std::fs::write(&pid_path, pid.to_string())?;
-    println!("Proxy started on http://127.0.0.1:{} (PID {})", port, pid);
+
+    let deadline = Instant::now() + STARTUP_TIMEOUT;
+    loop {
+        if let Some(status) = child.try_wait()? {
+            let _ = std::fs::remove_file(&pid_path);
+            bail!(
+                "Proxy exited during startup ({}). See {} or run 'claude-model-switch start --port {} --foreground'.",
+                status,
+                log_path.display(),
+                port
+            );
+        }
+        match health::check(&url) {
+            ProxyHealth::Healthy(info) if info.pid == pid => break,
+            _ if Instant::now() >= deadline => {
+                let _ = child.kill();
+                let _ = std::fs::remove_file(&pid_path);
+                bail!(
+                    "Proxy did not become healthy at {} within {}s",
+                    url,
+                    STARTUP_TIMEOUT.as_secs()
+                );
+            }
+            _ => std::thread::sleep(Duration::from_millis(100)),
+        }
+    }
+    println!("Proxy started on {} (PID {})", url, pid);
+    println!("Logging to {}", log_path.display());
+    match cassettes {
+        Some(CassetteMode::Record(dir)) => println!("Recording exchanges to {}", dir.display()),
+        Some(CassetteMode::Replay(dir)) => println!("Replaying exchanges from {}", dir.display()),
+        None => {}
+    }
     Ok(())
 }
 
+/// Removes the PID file when the proxy exits, if it still names this
+/// process, and the admin endpoint file if it still holds this proxy's
+/// token. `stop` waits for this rather than deleting the files itself.
+#[derive(Default)]
+pub struct PidFileGuard {
+    pub admin_endpoint: Option<AdminEndpoint>,
+}
+
+impl Drop for PidFileGuard {
+    fn drop(&mut self) {
+        if let Some(ours) = &self.admin_endpoint {
+            let published = AdminEndpoint::path().ok().and_then(|path| {
+                let content = std::fs::read_to_string(&path).ok()?;
+                let endpoint: AdminEndpoint = serde_json::from_str(&content).ok()?;
+                Some((path, endpoint))
+            });
+            if let Some((path, endpoint)) = published {
+                if endpoint.token == ours.token {
+                    let _ = std::fs::remove_file(path);
+                }
+            }
+        }
+        let Ok(pid_path) = pid_file_path() else {
+            return;
+        };
+        let ours = std::fs::read_to_string(&pid_path)
+            .ok()
+            .and_then(|pid| pid.trim().parse::<u32>().ok())
+            == Some(std::process::id());
+        if ours {
+            let _ = std::fs::remove_file(&pid_path);
+        }
+    }
+}
+
+/// Ask the process to shut down gracefully.
+fn terminate(pid: u32) -> bool {
+    #[cfg(unix)]
+    unsafe {
+        libc::kill(pid as i32, libc::SIGTERM) == 0
+    }
+    #[cfg(not(unix))]
+    Command::new("kill")
+        .arg(pid.to_string())
+        .status()
+        .map(|s| s.success())
+        .unwrap_or(false)
+}
+
 pub fn stop_daemon() -> Result<()> {
     let pid_path = pid_file_path()?;
 
@@ -47,13 +208,49 @@ pub fn stop_daemon() -> Result<()> {
     let pid_str = std::fs::read_to_string(&pid_path)?;
     let pid: u32 = pid_str.trim().parse().context("Invalid PID file")?;
 
-    let status = Command::new("kill").arg(pid.to_string()).status()?;
-    std::fs::remove_file(&pid_path)?;
-
-    if status.success() {
-        println!("Proxy stopped (PID {}).", pid);
-    } else {
+    // Ask before signalling: once draining, the proxy stops answering.
+    let in_flight = AdminClient::discover()
+        .and_then(|client| client.in_flight().ok().flatten())
+        .unwrap_or(0);
+    if !process_alive(pid) || !terminate(pid) {
+        std::fs::remove_file(&pid_path)?;
         println!("Process {} was not running. Cleaned up PID file.", pid);
+        return Ok(());
+    }
+
+    // The proxy drains for up to its grace period; allow a little longer
+    // for it to exit.
+    let grace = ProfileConfig::load()
+        .map(|config| config.shutdown_grace())
+        .unwrap_or_else(|_| ProfileConfig::default().shutdown_grace());
+    if in_flight > 0 {
+        println!(
+            "Waiting up to {}s for {} active request(s) to finish...",
+            grace.as_secs(),
+            in_flight
+        );
+    }
+    let started = Instant::now();
+    let deadline = started + grace + STOP_MARGIN;
+    while process_alive(pid) {
+        if Instant::now() >= deadline {
+            bail!(
+                "Proxy (PID {}) did not exit within {}s. Force it with: kill -9 {}",
+                pid,
+                (grace + STOP_MARGIN).as_secs(),
+                pid
+            );
+        }
+        std::thread::sleep(Duration::from_millis(100));
+    }
+    // Normally already gone; the proxy cannot clean up after SIGKILL.
+    if pid_path.exists() {
+        let _ = std::fs::remove_file(&pid_path);
     }
+    println!(
+        "Proxy stopped (PID {}) after {:.1}s.",
+        pid,
+        started.elapsed().as_secs_f64()
+    );
     Ok(())
 }
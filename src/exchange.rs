@@ -0,0 +1,373 @@
+//! Per-request bookkeeping that follows a response body to completion, so
+//! streamed and unary responses are accounted for the same way.
+
+use bytes::Bytes;
+use http_body_util::BodyExt;
+use hyper::body::Frame;
+use hyper::HeaderMap;
+use serde::Serialize;
+use serde_json::{json, Value};
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{self, Poll};
+use std::time::{Duration, Instant, SystemTime};
+
+use crate::budget::BudgetLedger;
+use crate::metrics::Metrics;
+use crate::proxy::{BoxError, ProxyBody};
+use crate::rewrite::classify_model;
+use crate::time::format_rfc3339;
+use crate::transcript::{self, TranscriptSettings};
+use crate::usage::{self, UsageRecord};
+
+/// The provider name recorded for responses the proxy answers itself, such
+/// as rejections and locally counted tokens.
+pub const LOCAL_PROVIDER: &str = "proxy";
+
+/// Token usage as reported in Anthropic `usage` objects.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct Usage {
+    pub input_tokens: u64,
+    pub output_tokens: u64,
+    pub cache_creation_input_tokens: u64,
+    pub cache_read_input_tokens: u64,
+}
+
+impl Usage {
+    /// Fold in a `usage` object. Streams report input tokens on
+    /// `message_start` and cumulative output tokens on `message_delta`, so
+    /// non-zero values overwrite earlier ones.
+    fn merge(&mut self, usage: &Value) {
+        let fields = [
+            ("input_tokens", &mut self.input_tokens),
+            ("output_tokens", &mut self.output_tokens),
+            (
+                "cache_creation_input_tokens",
+                &mut self.cache_creation_input_tokens,
+            ),
+            ("cache_read_input_tokens", &mut self.cache_read_input_tokens),
+        ];
+        for (name, field) in fields {
+            if let Some(value) = usage.get(name).and_then(Value::as_u64) {
+                if value > 0 {
+                    *field = value;
+                }
+            }
+        }
+    }
+}
+
+/// Extracts `usage` from an Anthropic response body, either a unary JSON
+/// message or an SSE stream of message events.
+#[derive(Debug, Default)]
+pub struct UsageScanner {
+    event_stream: bool,
+    buffer: Vec<u8>,
+    usage: Option<Usage>,
+}
+
+impl UsageScanner {
+    pub fn new(event_stream: bool) -> Self {
+        Self {
+            event_stream,
+            ..Default::default()
+        }
+    }
+
+    pub fn push(&mut self, chunk: &[u8]) {
+        self.buffer.extend_from_slice(chunk);
+        if !self.event_stream {
+            return;
+        }
+        while let Some(pos) = self.buffer.iter().position(|&b| b == b'\n') {
+            let line: Vec<u8> = self.buffer.drain(..=pos).collect();
+            let Some(data) = line.strip_prefix(b"data:") else {
+                continue;
+            };
+            let Ok(event) = serde_json::from_slice::<Value>(data) else {
+                continue;
+            };
+            let usage = match event.get("type").and_then(Value::as_str) {
+                Some("message_start") => event.pointer("/message/usage"),
+                Some("message_delta") => event.get("usage"),
+                _ => None,
+            };
+            if let Some(usage) = usage {
+                self.usage.get_or_insert_with(Usage::default).merge(usage);
+            }
+        }
+    }
+
+    pub fn finish(&mut self) -> Option<Usage> {
+        if !self.event_stream {
+            let body = std::mem::take(&mut self.buffer);
+            if let Some(usage) = serde_json::from_slice::<Value>(&body)
+                .ok()
+                .as_ref()
+                .and_then(|json| json.get("usage"))
+            {
+                self.usage.get_or_insert_with(Usage::default).merge(usage);
+            }
+        }
+        self.usage
+    }
+}
+
+/// What the proxy knows about one request/response pair.
+#[derive(Debug)]
+pub struct Exchange {
+    pub timestamp: SystemTime,
+    pub started: Instant,
+    pub method: String,
+    pub route: String,
+    pub provider: String,
+    pub model: Option<String>,
+    pub upstream_model: Option<String>,
+    pub status: u16,
+    pub stream: bool,
+    pub request_headers: HeaderMap,
+    pub request_body: Bytes,
+    pub transcript: Option<TranscriptSettings>,
+    /// Where successful responses' token usage is appended.
+    pub usage_store: Option<PathBuf>,
+    /// Running spend to charge the response's cost to.
+    pub budgets: Option<Arc<BudgetLedger>>,
+    pub metrics: Option<Arc<Metrics>>,
+}
+
+/// Observation state that lives alongside the response body.
+struct Observer {
+    exchange: Exchange,
+    scanner: UsageScanner,
+    first_byte: Option<Duration>,
+    captured: Option<Vec<u8>>,
+}
+
+impl Observer {
+    fn on_data(&mut self, data: &[u8]) {
+        if self.first_byte.is_none() {
+            self.first_byte = Some(self.exchange.started.elapsed());
+        }
+        self.scanner.push(data);
+        if let Some(captured) = &mut self.captured {
+            captured.extend_from_slice(data);
+        }
+    }
+
+    /// Called exactly once, when the body ends or is dropped early because
+    /// the client went away.
+    fn finish(mut self, completed: bool) {
+        let usage = self.scanner.finish();
+        let latency = self.exchange.started.elapsed();
+        if !completed {
+            // Dropping the body dropped the upstream stream, so the provider
+            // connection is already closed.
+            eprintln!(
+                "[proxy] response from {} ended early after {:.1}s; upstream connection closed",
+                self.exchange.provider,
+                latency.as_secs_f64()
+            );
+        }
+        let transcript = self.exchange.transcript.as_ref().map(|settings| {
+            let record = self.transcript_record(settings, usage, latency, completed);
+            (settings.path.clone(), record)
+        });
+        if let Some(metrics) = &self.exchange.metrics {
+            metrics.record_response(
+                &self.exchange.provider,
+                self.exchange.status,
+                latency,
+                self.first_byte,
+                usage,
+            );
+        }
+        let record = usage
+            .filter(|_| (200..300).contains(&self.exchange.status))
+            .map(|usage| self.usage_record(&usage));
+        if let (Some(record), Some(budgets)) = (&record, &self.exchange.budgets) {
+            budgets.record(record);
+        }
+        write_records(transcript, record.zip(self.exchange.usage_store.clone()));
+    }
+
+    fn usage_record(&self, usage: &Usage) -> UsageRecord {
+        let exchange = &self.exchange;
+        let requested = exchange.model.as_deref().unwrap_or("unknown");
+        UsageRecord::new(
+            exchange.timestamp,
+            &exchange.provider,
+            exchange.upstream_model.as_deref().unwrap_or(requested),
+            classify_model(requested),
+            usage,
+        )
+    }
+
+    fn transcript_record(
+        &self,
+        settings: &TranscriptSettings,
+        usage: Option<Usage>,
+        latency: Duration,
+        completed: bool,
+    ) -> Value {
+        let exchange = &self.exchange;
+        let mut record = json!({
+            "timestamp": format_rfc3339(exchange.timestamp),
+            "method": exchange.method,
+            "route": exchange.route,
+            "provider": exchange.provider,
+            "model": exchange.model,
+            "upstream_model": exchange.upstream_model,
+            "status": exchange.status,
+            "stream": exchange.stream,
+            "latency_ms": latency.as_millis() as u64,
+            "first_byte_ms": self.first_byte.map(|d| d.as_millis() as u64),
+            "usage": usage,
+            "completed": completed,
+        });
+        if settings.include_bodies {
+            record["request_headers"] =
+                transcript::redact_headers(&exchange.request_headers, &settings.secrets);
+            record["request"] = transcript::body_value(&exchange.request_body, &settings.secrets);
+            if let Some(captured) = &self.captured {
+                record["response"] = transcript::body_value(captured, &settings.secrets);
+            }
+        }
+        record
+    }
+}
+
+/// Append the transcript and usage lines on the blocking pool: `finish` runs
+/// inside a body poll or drop on an async worker, which must not wait on disk.
+fn write_records(transcript: Option<(PathBuf, Value)>, usage: Option<(UsageRecord, PathBuf)>) {
+    if transcript.is_none() && usage.is_none() {
+        return;
+    }
+    let write = move || {
+        if let Some((path, record)) = transcript {
+            if let Err(e) = transcript::append(&path, &record) {
+                eprintln!("[proxy] failed to write transcript: {:#}", e);
+            }
+        }
+        if let Some((record, path)) = usage {
+            if let Err(e) = usage::append(&path, &record) {
+                eprintln!("[proxy] failed to record usage: {:#}", e);
+            }
+        }
+    };
+    match tokio::runtime::Handle::try_current() {
+        Ok(runtime) => drop(runtime.spawn_blocking(write)),
+        Err(_) => write(),
+    }
+}
+
+struct ObservedBody {
+    inner: ProxyBody,
+    observer: Option<Observer>,
+}
+
+impl hyper::body::Body for ObservedBody {
+    type Data = Bytes;
+    type Error = BoxError;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut task::Context<'_>,
+    ) -> Poll<Option<Result<Frame<Bytes>, BoxError>>> {
+        let poll = Pin::new(&mut self.inner).poll_frame(cx);
+        let ended = match &poll {
+            Poll::Ready(Some(Ok(frame))) => {
+                if let (Some(data), Some(observer)) = (frame.data_ref(), self.observer.as_mut()) {
+                    observer.on_data(data);
+                }
+                // hyper stops polling once the body reports its end, so a
+                // single-frame body never yields `None`.
+                self.inner.is_end_stream()
+            }
+            Poll::Ready(None) => true,
+            _ => false,
+        };
+        if ended {
+            if let Some(observer) = self.observer.take() {
+                observer.finish(true);
+            }
+        }
+        poll
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> hyper::body::SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+impl Drop for ObservedBody {
+    fn drop(&mut self) {
+        if let Some(observer) = self.observer.take() {
+            observer.finish(false);
+        }
+    }
+}
+
+/// Wrap a response body so the exchange is recorded once the body has been
+/// fully sent, or abandoned by the client.
+pub fn observe(body: ProxyBody, exchange: Exchange) -> ProxyBody {
+    let capture = exchange
+        .transcript
+        .as_ref()
+        .map(|t| t.include_bodies)
+        .unwrap_or(false);
+    let observer = Observer {
+        scanner: UsageScanner::new(exchange.stream),
+        exchange,
+        first_byte: None,
+        captured: capture.then(Vec::new),
+    };
+    ObservedBody {
+        inner: body,
+        observer: Some(observer),
+    }
+    .boxed_unsync()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unary_usage_is_parsed_at_finish() {
+        let mut scanner = UsageScanner::new(false);
+        scanner.push(br#"{"type":"message","usage":{"input_tokens":10,"#);
+        scanner.push(br#""output_tokens":4,"cache_read_input_tokens":2}}"#);
+        assert_eq!(
+            scanner.finish(),
+            Some(Usage {
+                input_tokens: 10,
+                output_tokens: 4,
+                cache_creation_input_tokens: 0,
+                cache_read_input_tokens: 2,
+            })
+        );
+    }
+
+    #[test]
+    fn streamed_usage_combines_start_and_delta() {
+        let mut scanner = UsageScanner::new(true);
+        scanner.push(b"event: message_start\ndata: {\"type\":\"message_start\",\"message\":{\"usage\":{\"input_tokens\":25,\"output_tokens\":1}}}\n\n");
+        scanner.push(b"event: content_block_delta\ndata: {\"type\":\"content_block_delta\",\"delta\":{\"text\":\"hi\"}}\n\nevent: message_delta\ndata: {\"type\":\"message_delta\",");
+        scanner.push(b"\"usage\":{\"output_tokens\":15}}\n\n");
+        let usage = scanner.finish().unwrap();
+        assert_eq!(usage.input_tokens, 25);
+        assert_eq!(usage.output_tokens, 15);
+    }
+
+    #[test]
+    fn bodies_without_usage_report_none() {
+        let mut scanner = UsageScanner::new(false);
+        scanner.push(br#"{"data":[]}"#);
+        assert_eq!(scanner.finish(), None);
+    }
+}
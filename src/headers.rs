@@ -0,0 +1,142 @@
+//! Per-provider header overrides. Values in `headers` are templates where
+//! `{env:NAME}` reads an environment variable and `{provider}`, `{route}`,
+//! `{role}`, `{model}` and `{upstream_model}` describe the request.
+
+use anyhow::{bail, Context, Result};
+use hyper::header::{HeaderName, HeaderValue};
+use hyper::HeaderMap;
+
+use crate::config::Provider;
+
+/// What a header template can refer to, for one upstream attempt.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TemplateContext<'a> {
+    pub provider: &'a str,
+    /// The inbound path, such as `/v1/messages`.
+    pub route: &'a str,
+    /// The tier the requested model maps to: haiku, sonnet or opus.
+    pub role: Option<&'a str>,
+    /// The model as Claude Code asked for it.
+    pub model: Option<&'a str>,
+    /// The model sent upstream after tier rewriting.
+    pub upstream_model: Option<&'a str>,
+}
+
+/// Split a template into literal text and `{placeholder}` names.
+fn parse(template: &str) -> Result<Vec<(bool, &str)>> {
+    let mut parts = Vec::new();
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        let end = rest[start..]
+            .find('}')
+            .with_context(|| format!("Unclosed '{{' in header template '{}'", template))?;
+        parts.push((false, &rest[..start]));
+        parts.push((true, &rest[start + 1..start + end]));
+        rest = &rest[start + end + 1..];
+    }
+    parts.push((false, rest));
+    Ok(parts)
+}
+
+/// Check a template without rendering it, for config validation.
+pub fn check_template(template: &str) -> Result<()> {
+    for (placeholder, name) in parse(template)? {
+        if placeholder
+            && name.strip_prefix("env:").is_none_or(str::is_empty)
+            && !matches!(
+                name,
+                "provider" | "route" | "role" | "model" | "upstream_model"
+            )
+        {
+            bail!("Unknown placeholder '{{{}}}'", name);
+        }
+    }
+    Ok(())
+}
+
+pub fn render(template: &str, ctx: &TemplateContext) -> Result<String> {
+    let mut out = String::new();
+    for (placeholder, name) in parse(template)? {
+        if !placeholder {
+            out.push_str(name);
+            continue;
+        }
+        let value = match name {
+            "provider" => ctx.provider.to_string(),
+            "route" => ctx.route.to_string(),
+            "role" => ctx.role.unwrap_or_default().to_string(),
+            "model" => ctx.model.unwrap_or_default().to_string(),
+            "upstream_model" => ctx.upstream_model.unwrap_or_default().to_string(),
+            _ => match name.strip_prefix("env:") {
+                Some(var) => std::env::var(var)
+                    .with_context(|| format!("Environment variable {} is not set", var))?,
+                None => bail!("Unknown placeholder '{{{}}}'", name),
+            },
+        };
+        out.push_str(&value);
+    }
+    Ok(out)
+}
+
+/// Drop the provider's `remove_headers`, then set its `headers`, replacing
+/// any header of the same name.
+pub fn apply(headers: &mut HeaderMap, provider: &Provider, ctx: &TemplateContext) -> Result<()> {
+    for name in &provider.remove_headers {
+        headers.remove(name.to_ascii_lowercase().as_str());
+    }
+    for (name, template) in &provider.headers {
+        let header = HeaderName::try_from(name.as_str())
+            .with_context(|| format!("Invalid header name '{}'", name))?;
+        let value = render(template, ctx).with_context(|| format!("Header {}", name))?;
+        let value = HeaderValue::try_from(value)
+            .with_context(|| format!("Header {} renders to an invalid value", name))?;
+        headers.insert(header, value);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+
+    #[test]
+    fn renders_request_and_env_placeholders() {
+        let ctx = TemplateContext {
+            provider: "gw",
+            route: "/v1/messages",
+            role: Some("opus"),
+            model: Some("claude-opus-4-1"),
+            upstream_model: Some("glm-4.7"),
+        };
+        assert_eq!(
+            render("{env:HOME}/{provider}/{role}/{upstream_model}", &ctx).unwrap(),
+            format!("{}/gw/opus/glm-4.7", std::env::var("HOME").unwrap())
+        );
+        assert!(render("{env:CMS_TEST_UNSET_VAR}", &ctx).is_err());
+        assert!(check_template("{tenant}").is_err());
+        assert!(check_template("{route").is_err());
+        assert!(check_template("plain {model}").is_ok());
+    }
+
+    #[test]
+    fn removes_then_sets() {
+        let provider = Provider {
+            headers: BTreeMap::from([
+                ("X-Title".to_string(), "claude-model-switch".to_string()),
+                ("anthropic-version".to_string(), "2023-06-01".to_string()),
+            ]),
+            remove_headers: vec!["Anthropic-Beta".to_string(), "user-agent".to_string()],
+            ..Default::default()
+        };
+        let mut headers = HeaderMap::new();
+        headers.insert("anthropic-beta", HeaderValue::from_static("tools"));
+        headers.insert("anthropic-version", HeaderValue::from_static("2024-01-01"));
+        headers.insert("accept", HeaderValue::from_static("*/*"));
+        apply(&mut headers, &provider, &TemplateContext::default()).unwrap();
+        assert!(headers.get("anthropic-beta").is_none());
+        assert_eq!(headers["anthropic-version"], "2023-06-01");
+        assert_eq!(headers["x-title"], "claude-model-switch");
+        assert_eq!(headers["accept"], "*/*");
+    }
+}
@@ -0,0 +1,273 @@
+//! `/healthz` and `/readyz` on the proxy, and the checks the CLI runs
+//! against them to tell a healthy proxy from a stale PID file or a port
+//! held by something else.
+
+use anyhow::Result;
+use hyper::Response;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::admin::AdminEndpoint;
+use crate::config::Provider;
+use crate::proxy::{json_response, ProxyBody, ProxyState};
+
+pub const HEALTH_PATH: &str = "/healthz";
+pub const READY_PATH: &str = "/readyz";
+
+/// Identifies this program in `/healthz`, so a different server on the same
+/// port is not mistaken for the proxy.
+pub const SERVICE_NAME: &str = "claude-model-switch";
+
+const PROBE_TIMEOUT: Duration = Duration::from_secs(2);
+
+pub fn health_response() -> Response<ProxyBody> {
+    json_response(
+        200,
+        &json!({
+            "status": "ok",
+            "service": SERVICE_NAME,
+            "version": env!("CARGO_PKG_VERSION"),
+            "pid": std::process::id(),
+        }),
+    )
+}
+
+/// Whether a TCP connection to the provider's host can be opened.
+async fn probe_direct(base_url: &str) -> Value {
+    let started = Instant::now();
+    let target = url::Url::parse(base_url).ok().and_then(|url| {
+        Some(format!(
+            "{}:{}",
+            url.host_str()?,
+            url.port_or_known_default()?
+        ))
+    });
+    let Some(target) = target else {
+        return json!({ "reachable": false, "error": "invalid base_url" });
+    };
+    match tokio::time::timeout(PROBE_TIMEOUT, tokio::net::TcpStream::connect(&target)).await {
+        Ok(Ok(_)) => json!({
+            "reachable": true,
+            "latency_ms": started.elapsed().as_millis() as u64,
+        }),
+        Ok(Err(e)) => json!({ "reachable": false, "error": e.to_string() }),
+        Err(_) => json!({ "reachable": false, "error": "connect timed out" }),
+    }
+}
+
+/// Whether the provider answers a HEAD request sent the way real requests
+/// go. Any HTTP status counts as reachable.
+async fn probe_via_client(client: &reqwest::Client, base_url: &str) -> Value {
+    let started = Instant::now();
+    match client.head(base_url).timeout(PROBE_TIMEOUT).send().await {
+        Ok(_) => json!({
+            "reachable": true,
+            "latency_ms": started.elapsed().as_millis() as u64,
+        }),
+        Err(e) => json!({
+            "reachable": false,
+            "error": format!("{:#}", anyhow::Error::from(e.without_url())),
+        }),
+    }
+}
+
+/// Providers behind an outbound proxy are probed through it; a direct
+/// connection says nothing about the route their requests take.
+async fn probe(state: &ProxyState, name: &str, provider: &Provider) -> Value {
+    let proxied = provider
+        .network
+        .as_ref()
+        .is_some_and(|n| n.http_proxy.is_some() || n.socks_proxy.is_some());
+    if !proxied {
+        return probe_direct(&provider.base_url).await;
+    }
+    match state.client_for(name, provider) {
+        Ok(client) => probe_via_client(&client, &provider.base_url).await,
+        Err(e) => json!({ "reachable": false, "error": format!("{:#}", e) }),
+    }
+}
+
+/// Ready when the loaded config validates, the last reload succeeded and
+/// the active provider is reachable.
+pub async fn ready_response(state: Arc<ProxyState>) -> Response<ProxyBody> {
+    let (active, errors, providers) = {
+        let config = state.config.read().await;
+        let providers: Vec<(String, Provider)> = config
+            .providers
+            .iter()
+            .map(|(name, p)| (name.clone(), p.clone()))
+            .collect();
+        (config.active.clone(), config.validate(), providers)
+    };
+    let reload_error = state.last_reload_error.lock().unwrap().clone();
+
+    let probes = futures_util::future::join_all(
+        providers
+            .iter()
+            .map(|(name, provider)| probe(&state, name, provider)),
+    )
+    .await;
+    let upstreams: serde_json::Map<String, Value> = providers
+        .into_iter()
+        .map(|(name, _)| name)
+        .zip(probes)
+        .collect();
+    let active_reachable = upstreams
+        .get(&active)
+        .and_then(|p| p["reachable"].as_bool())
+        .unwrap_or(false);
+
+    let ready = errors.is_empty() && reload_error.is_none() && active_reachable;
+    json_response(
+        if ready { 200 } else { 503 },
+        &json!({
+            "status": if ready { "ready" } else { "not_ready" },
+            "config": {
+                "active": active,
+                "errors": errors,
+                "last_reload_error": reload_error,
+            },
+            "providers": upstreams,
+        }),
+    )
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct HealthInfo {
+    pub service: String,
+    pub version: String,
+    pub pid: u32,
+}
+
+#[derive(Debug)]
+pub enum ProxyHealth {
+    Healthy(HealthInfo),
+    /// Something answered, but it is not this proxy.
+    Foreign,
+    Unreachable,
+}
+
+/// Base URL of the local proxy: the published admin endpoint if there is
+/// one, otherwise the default port.
+pub fn proxy_url() -> String {
+    AdminEndpoint::discover()
+        .map(|endpoint| endpoint.url)
+        .unwrap_or_else(|| "http://127.0.0.1:4000".to_string())
+}
+
+async fn get_json(url: &str) -> Result<Option<(u16, Value)>> {
+    let client = reqwest::Client::builder()
+        .timeout(Duration::from_secs(5))
+        .build()?;
+    match client.get(url).send().await {
+        Ok(response) => {
+            let status = response.status().as_u16();
+            Ok(Some((status, response.json().await.unwrap_or(Value::Null))))
+        }
+        Err(e) if e.is_connect() || e.is_timeout() => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+fn block_on<T>(future: impl std::future::Future<Output = T>) -> Result<T> {
+    Ok(tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()?
+        .block_on(future))
+}
+
+pub fn check(base_url: &str) -> ProxyHealth {
+    let url = format!("{}{}", base_url.trim_end_matches('/'), HEALTH_PATH);
+    match block_on(get_json(&url)) {
+        Ok(Ok(Some((200, json)))) => match serde_json::from_value::<HealthInfo>(json) {
+            Ok(info) if info.service == SERVICE_NAME => ProxyHealth::Healthy(info),
+            _ => ProxyHealth::Foreign,
+        },
+        Ok(Ok(Some(_))) | Ok(Err(_)) => ProxyHealth::Foreign,
+        _ => ProxyHealth::Unreachable,
+    }
+}
+
+/// The `/readyz` report, or `None` if the proxy did not answer.
+pub fn readiness(base_url: &str) -> Option<(bool, Value)> {
+    let url = format!("{}{}", base_url.trim_end_matches('/'), READY_PATH);
+    match block_on(get_json(&url)) {
+        Ok(Ok(Some((status, json)))) => Some((status == 200, json)),
+        _ => None,
+    }
+}
+
+/// Providers the readiness report marks unreachable, sorted by name.
+pub fn unreachable_providers(report: &Value) -> Vec<String> {
+    let mut names: Vec<String> = report["providers"]
+        .as_object()
+        .map(|providers| {
+            providers
+                .iter()
+                .filter(|(_, p)| p["reachable"] != Value::Bool(true))
+                .map(|(name, _)| name.clone())
+                .collect()
+        })
+        .unwrap_or_default();
+    names.sort();
+    names
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn probe_reports_refused_and_invalid_urls() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let up = probe_direct(&format!("http://127.0.0.1:{}/v1", port)).await;
+        assert_eq!(up["reachable"], true);
+        drop(listener);
+        let down = probe_direct(&format!("http://127.0.0.1:{}/v1", port)).await;
+        assert_eq!(down["reachable"], false);
+        assert_eq!(probe_direct("not a url").await["error"], "invalid base_url");
+    }
+
+    #[tokio::test]
+    async fn proxied_providers_are_probed_through_the_proxy() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        // A forward proxy that answers every request itself.
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let proxy_url = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            while let Ok((mut socket, _)) = listener.accept().await {
+                let mut buf = [0u8; 1024];
+                let _ = socket.read(&mut buf).await;
+                let _ = socket
+                    .write_all(b"HTTP/1.1 404 Not Found\r\ncontent-length: 0\r\n\r\n")
+                    .await;
+            }
+        });
+
+        let provider = Provider {
+            // Only reachable through the proxy.
+            base_url: "http://upstream.invalid/v1".to_string(),
+            network: Some(crate::config::NetworkSettings {
+                http_proxy: Some(proxy_url),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        let state = ProxyState::new(crate::config::ProfileConfig::default());
+        assert_eq!(probe(&state, "corp", &provider).await["reachable"], true);
+        assert_eq!(probe_direct(&provider.base_url).await["reachable"], false);
+    }
+
+    #[test]
+    fn unreachable_providers_are_listed() {
+        let report = json!({"providers": {
+            "glm": {"reachable": false, "error": "refused"},
+            "claude": {"reachable": true, "latency_ms": 3},
+        }});
+        assert_eq!(unreachable_providers(&report), ["glm"]);
+    }
+}
@@ -0,0 +1,273 @@
+use anyhow::Result;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+use crate::config::{ProfileConfig, RateLimits};
+use crate::proxy::ProxyError;
+
+/// Continuously refilling bucket holding up to one minute's worth of budget.
+#[derive(Debug)]
+struct TokenBucket {
+    capacity: f64,
+    tokens: f64,
+    per_sec: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    fn per_minute(rate: u32) -> Self {
+        let capacity = f64::from(rate.max(1));
+        Self {
+            capacity,
+            tokens: capacity,
+            per_sec: capacity / 60.0,
+            updated: Instant::now(),
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.per_sec).min(self.capacity);
+        self.updated = now;
+    }
+
+    /// How long until `amount` is available, zero if it is now. Requests
+    /// larger than the bucket only wait for a full bucket.
+    fn wait_for(&mut self, amount: f64, now: Instant) -> Duration {
+        self.refill(now);
+        let amount = amount.min(self.capacity);
+        if self.tokens >= amount {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64((amount - self.tokens) / self.per_sec)
+        }
+    }
+
+    fn take(&mut self, amount: f64) {
+        self.tokens -= amount.min(self.capacity);
+    }
+}
+
+/// Limits for one provider.
+#[derive(Debug)]
+pub struct Limiter {
+    config: RateLimits,
+    requests: Option<Mutex<TokenBucket>>,
+    tokens: Option<Mutex<TokenBucket>>,
+    concurrency: Option<Arc<Semaphore>>,
+}
+
+/// Held for the lifetime of a request (including its response stream) to
+/// occupy a concurrency slot.
+pub struct LimitPermit {
+    _slot: Option<OwnedSemaphorePermit>,
+}
+
+impl Limiter {
+    fn new(config: &RateLimits) -> Self {
+        Self {
+            config: config.clone(),
+            requests: config
+                .rpm
+                .map(|rpm| Mutex::new(TokenBucket::per_minute(rpm))),
+            tokens: config
+                .tpm
+                .map(|tpm| Mutex::new(TokenBucket::per_minute(tpm))),
+            concurrency: config
+                .max_concurrency
+                .map(|n| Arc::new(Semaphore::new(n.max(1) as usize))),
+        }
+    }
+
+    /// Wait until the request fits every limit, or fail with a 429 once the
+    /// queue timeout elapses.
+    pub async fn acquire(&self, provider_name: &str, estimated_tokens: u64) -> Result<LimitPermit> {
+        let deadline = Instant::now() + Duration::from_millis(self.config.queue_timeout_ms);
+        let timed_out = || {
+            ProxyError::rate_limited(format!(
+                "Request to '{}' waited longer than {}ms for a rate limit slot",
+                provider_name, self.config.queue_timeout_ms
+            ))
+        };
+
+        let slot = match &self.concurrency {
+            Some(semaphore) => {
+                let wait = deadline.saturating_duration_since(Instant::now());
+                match tokio::time::timeout(wait, semaphore.clone().acquire_owned()).await {
+                    Ok(Ok(permit)) => Some(permit),
+                    _ => return Err(timed_out().into()),
+                }
+            }
+            None => None,
+        };
+
+        // Both buckets are checked under their locks before either is drawn
+        // from, so a request that times out waiting on tpm spends no rpm.
+        let amounts = [1.0, estimated_tokens as f64];
+        loop {
+            let now = Instant::now();
+            let wait = {
+                let mut buckets: Vec<_> = [&self.requests, &self.tokens]
+                    .into_iter()
+                    .zip(amounts)
+                    .filter_map(|(bucket, amount)| Some((bucket.as_ref()?.lock().unwrap(), amount)))
+                    .collect();
+                let wait = buckets
+                    .iter_mut()
+                    .map(|(bucket, amount)| bucket.wait_for(*amount, now))
+                    .max()
+                    .unwrap_or_default();
+                if wait.is_zero() {
+                    for (bucket, amount) in &mut buckets {
+                        bucket.take(*amount);
+                    }
+                }
+                wait
+            };
+            if wait.is_zero() {
+                break;
+            }
+            if now + wait > deadline {
+                return Err(timed_out().into());
+            }
+            tokio::time::sleep(wait).await;
+        }
+
+        Ok(LimitPermit { _slot: slot })
+    }
+}
+
+/// Limiters keyed by provider name, so `/v1` and `/p/<provider>/v1` traffic to
+/// the same provider share one budget. A limiter is rebuilt when its config
+/// changes on reload.
+#[derive(Default)]
+pub struct LimiterRegistry {
+    limiters: Mutex<HashMap<String, Arc<Limiter>>>,
+}
+
+impl LimiterRegistry {
+    pub fn get(&self, provider_name: &str, config: &RateLimits) -> Arc<Limiter> {
+        let mut limiters = self.limiters.lock().unwrap();
+        match limiters.get(provider_name) {
+            Some(limiter) if limiter.config == *config => limiter.clone(),
+            _ => {
+                let limiter = Arc::new(Limiter::new(config));
+                limiters.insert(provider_name.to_string(), limiter.clone());
+                limiter
+            }
+        }
+    }
+
+    /// Drop limiters for providers that are gone or whose limits changed, so
+    /// a provider re-added under the same name starts with fresh buckets.
+    /// Requests already holding a dropped limiter finish against it.
+    pub fn prune(&self, config: &ProfileConfig) {
+        self.limiters.lock().unwrap().retain(|name, limiter| {
+            config
+                .providers
+                .get(name)
+                .and_then(|provider| provider.limits.as_ref())
+                == Some(&limiter.config)
+        });
+    }
+}
+
+/// Rough input-token estimate for TPM accounting: about four bytes per token.
+pub fn estimate_tokens(body: &[u8]) -> u64 {
+    (body.len() as u64).div_ceil(4)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bucket_refills_over_time() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::per_minute(60);
+        bucket.updated = start;
+        assert!(bucket.wait_for(60.0, start).is_zero());
+        bucket.take(60.0);
+        assert_eq!(bucket.wait_for(1.0, start), Duration::from_secs(1));
+        assert!(bucket
+            .wait_for(1.0, start + Duration::from_secs(1))
+            .is_zero());
+    }
+
+    #[test]
+    fn oversized_requests_wait_for_full_bucket() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::per_minute(10);
+        bucket.updated = start;
+        assert!(bucket.wait_for(500.0, start).is_zero());
+    }
+
+    #[tokio::test]
+    async fn concurrency_cap_times_out() {
+        let limiter = Limiter::new(&RateLimits {
+            max_concurrency: Some(1),
+            queue_timeout_ms: 20,
+            ..Default::default()
+        });
+        let held = limiter.acquire("p", 0).await.unwrap();
+        let err = limiter.acquire("p", 0).await.err().unwrap();
+        assert_eq!(err.downcast_ref::<ProxyError>().unwrap().status, 429);
+        drop(held);
+        assert!(limiter.acquire("p", 0).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn tpm_timeouts_do_not_spend_rpm() {
+        let limiter = Limiter::new(&RateLimits {
+            rpm: Some(2),
+            tpm: Some(100),
+            queue_timeout_ms: 20,
+            ..Default::default()
+        });
+        limiter.acquire("p", 100).await.unwrap();
+        assert!(limiter.acquire("p", 100).await.is_err());
+        // Refill only tpm: the rpm token the failed request left is still there.
+        limiter.tokens.as_ref().unwrap().lock().unwrap().tokens = 100.0;
+        assert!(limiter.acquire("p", 100).await.is_ok());
+    }
+
+    #[test]
+    fn registry_shares_and_rebuilds_limiters() {
+        let registry = LimiterRegistry::default();
+        let limits = RateLimits {
+            rpm: Some(10),
+            ..Default::default()
+        };
+        let a = registry.get("glm", &limits);
+        let b = registry.get("glm", &limits);
+        assert!(Arc::ptr_eq(&a, &b));
+        let changed = RateLimits {
+            rpm: Some(20),
+            ..Default::default()
+        };
+        assert!(!Arc::ptr_eq(&a, &registry.get("glm", &changed)));
+    }
+
+    #[test]
+    fn applied_configs_prune_the_registry() {
+        let registry = LimiterRegistry::default();
+        let limits = RateLimits {
+            rpm: Some(10),
+            ..Default::default()
+        };
+        let mut config = ProfileConfig::default();
+        let claude = config.providers.get_mut("claude").unwrap();
+        claude.limits = Some(limits.clone());
+        let kept = registry.get("claude", &limits);
+        registry.get("removed", &limits);
+        registry.prune(&config);
+        assert_eq!(registry.limiters.lock().unwrap().len(), 1);
+        assert!(Arc::ptr_eq(&kept, &registry.get("claude", &limits)));
+
+        config.providers.get_mut("claude").unwrap().limits = None;
+        registry.prune(&config);
+        assert!(registry.limiters.lock().unwrap().is_empty());
+    }
+}
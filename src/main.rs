@@ -1,12 +1,36 @@
+mod admin;
+mod betas;
+mod budget;
+mod capabilities;
+mod cassette;
 mod commands;
 mod config;
 mod daemon;
+mod exchange;
+mod headers;
+mod health;
+mod limits;
+mod metrics;
+mod mock;
+mod models;
+mod openai;
 mod orchestrator;
+mod outbound;
+mod paths;
 mod proxy;
+mod retry;
 mod rewrite;
+mod time;
+mod timeouts;
+mod tokens;
+mod transcript;
+mod usage;
 
+use cassette::CassetteMode;
 use clap::{Parser, Subcommand};
-use config::ProfileConfig;
+use config::{ApiFormat, ProfileConfig};
+use std::path::PathBuf;
+use std::time::Duration;
 
 #[derive(Parser)]
 #[command(
@@ -27,6 +51,12 @@ enum Commands {
         port: u16,
         #[arg(long, hide = true)]
         foreground: bool,
+        /// Save every upstream exchange as a cassette in this directory
+        #[arg(long, value_name = "DIR", conflicts_with = "replay")]
+        record: Option<PathBuf>,
+        /// Serve exchanges from cassettes in this directory instead of upstream
+        #[arg(long, value_name = "DIR")]
+        replay: Option<PathBuf>,
     },
     /// Stop the proxy server
     Stop,
@@ -66,15 +96,56 @@ enum Commands {
         /// Optional bearer token to save immediately.
         #[arg(long)]
         auth_token: Option<String>,
+        /// Upstream API format: anthropic or openai (Chat Completions).
+        #[arg(long)]
+        api_format: Option<ApiFormat>,
     },
     /// Remove a provider
     Remove { name: String },
     /// List available providers
     List,
     /// Show current status
-    Status,
+    Status {
+        /// Only verify that the proxy is running and healthy; exits non-zero
+        /// if it is not.
+        #[arg(long)]
+        check: bool,
+    },
     /// First-time setup
     Init,
+    /// Report recorded token usage and cost
+    Usage {
+        /// First day to include (YYYY-MM-DD, UTC).
+        #[arg(long)]
+        since: Option<String>,
+        /// Last day to include (YYYY-MM-DD, UTC).
+        #[arg(long)]
+        until: Option<String>,
+        /// Only include this provider.
+        #[arg(long)]
+        provider: Option<String>,
+        /// Grouping columns: provider, model, tier, day (default: provider,model).
+        #[arg(long, value_delimiter = ',')]
+        by: Vec<usage::GroupBy>,
+        /// Output format: table, json or csv.
+        #[arg(long, default_value = "table")]
+        format: usage::OutputFormat,
+    },
+    /// Serve a fake Anthropic/OpenAI upstream for local development and tests
+    MockUpstream {
+        #[arg(short, long, default_value = "4100")]
+        port: u16,
+        /// JSON array of scripted replies, served in order and repeated
+        /// (default: echo the last user message).
+        #[arg(long, value_name = "FILE")]
+        script: Option<PathBuf>,
+        /// Delay before every response, in milliseconds.
+        #[arg(long, default_value = "0")]
+        latency_ms: u64,
+        /// Delay between streamed events, in milliseconds.
+        #[arg(long, default_value = "0")]
+        chunk_delay_ms: u64,
+    },
     /// Multi-agent tmux orchestration
     Orchestrate {
         #[command(subcommand)]
@@ -141,12 +212,12 @@ fn main() -> anyhow::Result<()> {
     match cli.command {
         Commands::Init => commands::cmd_init(),
         Commands::List => {
-            let config = ProfileConfig::load()?;
+            let config = admin::with_live_state(ProfileConfig::load()?);
             commands::cmd_list(&config)
         }
-        Commands::Status => {
-            let config = ProfileConfig::load()?;
-            commands::cmd_status(&config)
+        Commands::Status { check } => {
+            let config = admin::with_live_state(ProfileConfig::load()?);
+            commands::cmd_status(&config, check)
         }
         Commands::Use { provider } => {
             let mut config = ProfileConfig::load()?;
@@ -170,6 +241,7 @@ fn main() -> anyhow::Result<()> {
             opus,
             api_key,
             auth_token,
+            api_format,
         } => {
             let mut config = ProfileConfig::load()?;
             commands::cmd_add(
@@ -183,20 +255,61 @@ fn main() -> anyhow::Result<()> {
                 opus.as_deref(),
                 api_key,
                 auth_token,
+                api_format,
             )
         }
         Commands::Remove { name } => {
             let mut config = ProfileConfig::load()?;
             commands::cmd_remove(&mut config, &name)
         }
-        Commands::Start { port, foreground } => {
+        Commands::Start {
+            port,
+            foreground,
+            record,
+            replay,
+        } => {
+            let cassettes = record
+                .map(CassetteMode::Record)
+                .or(replay.map(CassetteMode::Replay));
             if foreground {
-                tokio::runtime::Runtime::new()?.block_on(proxy::run_proxy(port))
+                tokio::runtime::Runtime::new()?.block_on(proxy::run_proxy(port, cassettes))
             } else {
-                daemon::start_daemon(port)
+                daemon::start_daemon(port, cassettes.as_ref())
             }
         }
         Commands::Stop => daemon::stop_daemon(),
+        Commands::Usage {
+            since,
+            until,
+            provider,
+            by,
+            format,
+        } => {
+            let config = ProfileConfig::load()?;
+            let filter = usage::UsageFilter {
+                since,
+                until,
+                provider,
+            };
+            usage::cmd_usage(&config, filter, &by, format)
+        }
+        Commands::MockUpstream {
+            port,
+            script,
+            latency_ms,
+            chunk_delay_ms,
+        } => {
+            let script = match script {
+                Some(path) => mock::MockUpstream::load_script(&path)?,
+                None => Vec::new(),
+            };
+            let mock = mock::MockUpstream::new(
+                script,
+                Duration::from_millis(latency_ms),
+                Duration::from_millis(chunk_delay_ms),
+            );
+            tokio::runtime::Runtime::new()?.block_on(mock::run(port, mock))
+        }
         Commands::Orchestrate { command } => match command {
             OrchestrateCommands::Start {
                 session,
@@ -0,0 +1,308 @@
+//! Prometheus metrics for the proxy, served as text from `/metrics`.
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicI64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::exchange::{self, Usage};
+
+pub const METRICS_PATH: &str = "/metrics";
+pub const CONTENT_TYPE: &str = "text/plain; version=0.0.4; charset=utf-8";
+
+/// Upper bounds in seconds. Generous at the top end because long streamed
+/// responses routinely run for minutes.
+const BUCKETS: [f64; 12] = [
+    0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0, 120.0, 300.0,
+];
+
+#[derive(Debug, Clone, Default)]
+struct Histogram {
+    /// Observations per bucket, not cumulative; the last slot is `+Inf`.
+    counts: [u64; BUCKETS.len() + 1],
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    fn observe(&mut self, value: f64) {
+        let slot = BUCKETS
+            .iter()
+            .position(|&bound| value <= bound)
+            .unwrap_or(BUCKETS.len());
+        self.counts[slot] += 1;
+        self.sum += value;
+        self.count += 1;
+    }
+}
+
+#[derive(Debug, Default)]
+struct Registry {
+    requests: BTreeMap<(String, u16), u64>,
+    upstream_errors: BTreeMap<(String, String), u64>,
+    tokens: BTreeMap<(String, &'static str), u64>,
+    duration: BTreeMap<String, Histogram>,
+    first_byte: BTreeMap<String, Histogram>,
+}
+
+#[derive(Debug, Default)]
+pub struct Metrics {
+    in_flight: AtomicI64,
+    registry: Mutex<Registry>,
+}
+
+/// Counts a request as in flight until dropped, which for a successful
+/// response is when its body finishes or the client disconnects.
+pub struct InFlight(Arc<Metrics>);
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        self.0.in_flight.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl Metrics {
+    pub fn in_flight(self: &Arc<Self>) -> InFlight {
+        self.in_flight.fetch_add(1, Ordering::Relaxed);
+        InFlight(self.clone())
+    }
+
+    /// A response relayed to the client, recorded once its body is done.
+    pub fn record_response(
+        &self,
+        provider: &str,
+        status: u16,
+        duration: Duration,
+        first_byte: Option<Duration>,
+        usage: Option<Usage>,
+    ) {
+        let mut registry = self.registry.lock().unwrap();
+        *registry
+            .requests
+            .entry((provider.to_string(), status))
+            .or_default() += 1;
+        registry
+            .duration
+            .entry(provider.to_string())
+            .or_default()
+            .observe(duration.as_secs_f64());
+        if let Some(first_byte) = first_byte {
+            registry
+                .first_byte
+                .entry(provider.to_string())
+                .or_default()
+                .observe(first_byte.as_secs_f64());
+        }
+        if let Some(usage) = usage {
+            for (kind, count) in [
+                ("input", usage.input_tokens),
+                ("output", usage.output_tokens),
+                ("cache_creation", usage.cache_creation_input_tokens),
+                ("cache_read", usage.cache_read_input_tokens),
+            ] {
+                *registry
+                    .tokens
+                    .entry((provider.to_string(), kind))
+                    .or_default() += count;
+            }
+        }
+    }
+
+    /// An error the proxy answered itself, before any upstream response was
+    /// relayed.
+    pub fn record_rejected(&self, status: u16) {
+        *self
+            .registry
+            .lock()
+            .unwrap()
+            .requests
+            .entry((exchange::LOCAL_PROVIDER.to_string(), status))
+            .or_default() += 1;
+    }
+
+    /// A failed upstream attempt, including ones later retried or recovered
+    /// by a fallback. `reason` is the status code, `network` or `timeout`.
+    pub fn record_upstream_error(&self, provider: &str, reason: &str) {
+        *self
+            .registry
+            .lock()
+            .unwrap()
+            .upstream_errors
+            .entry((provider.to_string(), reason.to_string()))
+            .or_default() += 1;
+    }
+
+    /// Text exposition format.
+    pub fn render(&self) -> String {
+        let registry = self.registry.lock().unwrap();
+        let mut out = String::new();
+
+        header(
+            &mut out,
+            "cms_requests_total",
+            "counter",
+            "Requests answered, by provider and status.",
+        );
+        for ((provider, status), count) in &registry.requests {
+            let _ = writeln!(
+                out,
+                "cms_requests_total{{provider=\"{}\",status=\"{}\"}} {}",
+                escape(provider),
+                status,
+                count
+            );
+        }
+
+        header(
+            &mut out,
+            "cms_upstream_errors_total",
+            "counter",
+            "Failed upstream attempts, by provider and status code, network or timeout.",
+        );
+        for ((provider, reason), count) in &registry.upstream_errors {
+            let _ = writeln!(
+                out,
+                "cms_upstream_errors_total{{provider=\"{}\",reason=\"{}\"}} {}",
+                escape(provider),
+                escape(reason),
+                count
+            );
+        }
+
+        header(
+            &mut out,
+            "cms_in_flight_requests",
+            "gauge",
+            "Requests currently being proxied, including open streams.",
+        );
+        let _ = writeln!(
+            out,
+            "cms_in_flight_requests {}",
+            self.in_flight.load(Ordering::Relaxed)
+        );
+
+        header(
+            &mut out,
+            "cms_tokens_total",
+            "counter",
+            "Tokens reported by upstream usage, by provider and kind.",
+        );
+        for ((provider, kind), count) in &registry.tokens {
+            let _ = writeln!(
+                out,
+                "cms_tokens_total{{provider=\"{}\",kind=\"{}\"}} {}",
+                escape(provider),
+                kind,
+                count
+            );
+        }
+
+        histogram(
+            &mut out,
+            "cms_request_duration_seconds",
+            "Time from request to the end of the response body.",
+            &registry.duration,
+        );
+        histogram(
+            &mut out,
+            "cms_time_to_first_byte_seconds",
+            "Time from request to the first response body chunk.",
+            &registry.first_byte,
+        );
+        out
+    }
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+fn histogram(out: &mut String, name: &str, help: &str, series: &BTreeMap<String, Histogram>) {
+    header(out, name, "histogram", help);
+    for (provider, hist) in series {
+        let provider = escape(provider);
+        let mut cumulative = 0;
+        let bounds = BUCKETS
+            .iter()
+            .map(|b| b.to_string())
+            .chain(["+Inf".to_string()]);
+        for (bound, count) in bounds.zip(hist.counts) {
+            cumulative += count;
+            let _ = writeln!(
+                out,
+                "{}_bucket{{provider=\"{}\",le=\"{}\"}} {}",
+                name, provider, bound, cumulative
+            );
+        }
+        let _ = writeln!(
+            out,
+            "{}_sum{{provider=\"{}\"}} {}",
+            name, provider, hist.sum
+        );
+        let _ = writeln!(
+            out,
+            "{}_count{{provider=\"{}\"}} {}",
+            name, provider, hist.count
+        );
+    }
+}
+
+/// Escape a label value per the exposition format.
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn histogram_buckets_are_cumulative() {
+        let metrics = Metrics::default();
+        for ms in [30, 700, 400_000] {
+            metrics.record_response("glm", 200, Duration::from_millis(ms), None, None);
+        }
+        let text = metrics.render();
+        assert!(
+            text.contains("cms_request_duration_seconds_bucket{provider=\"glm\",le=\"0.05\"} 1")
+        );
+        assert!(text.contains("cms_request_duration_seconds_bucket{provider=\"glm\",le=\"1\"} 2"));
+        assert!(text.contains("cms_request_duration_seconds_bucket{provider=\"glm\",le=\"300\"} 2"));
+        assert!(
+            text.contains("cms_request_duration_seconds_bucket{provider=\"glm\",le=\"+Inf\"} 3")
+        );
+        assert!(text.contains("cms_request_duration_seconds_count{provider=\"glm\"} 3"));
+        assert!(text.contains("cms_requests_total{provider=\"glm\",status=\"200\"} 3"));
+    }
+
+    #[test]
+    fn in_flight_tracks_guards() {
+        let metrics = Arc::new(Metrics::default());
+        let a = metrics.in_flight();
+        let _b = metrics.in_flight();
+        drop(a);
+        assert!(metrics.render().contains("cms_in_flight_requests 1\n"));
+    }
+
+    #[test]
+    fn tokens_and_errors_are_labelled() {
+        let metrics = Metrics::default();
+        let usage = Usage {
+            input_tokens: 10,
+            output_tokens: 3,
+            ..Default::default()
+        };
+        metrics.record_response("a\"b", 200, Duration::ZERO, None, Some(usage));
+        metrics.record_upstream_error("glm", "503");
+        metrics.record_rejected(429);
+        let text = metrics.render();
+        assert!(text.contains("cms_tokens_total{provider=\"a\\\"b\",kind=\"input\"} 10"));
+        assert!(text.contains("cms_upstream_errors_total{provider=\"glm\",reason=\"503\"} 1"));
+        assert!(text.contains("cms_requests_total{provider=\"proxy\",status=\"429\"} 1"));
+    }
+}
@@ -0,0 +1,590 @@
+//! `mock-upstream`: a stand-in provider for local development and tests.
+//! Serves the Anthropic Messages and OpenAI Chat Completions APIs with
+//! echoed or scripted replies, streaming, tool calls, injected errors and
+//! latency.
+
+use anyhow::{Context, Result};
+use bytes::Bytes;
+use futures_util::StreamExt;
+use http_body_util::{BodyExt, StreamBody};
+use hyper::body::{Frame, Incoming};
+use hyper::server::conn::http1;
+use hyper::service::service_fn;
+use hyper::{HeaderMap, Method, Request, Response};
+use hyper_util::rt::TokioIo;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::net::TcpListener;
+
+use crate::proxy::{error_response, json_response, BoxError, ProxyBody};
+
+/// Fail this request with the given status instead of following the script.
+pub const ERROR_HEADER: &str = "x-mock-error";
+/// Wait this many milliseconds before answering this request.
+pub const LATENCY_HEADER: &str = "x-mock-latency-ms";
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct MockToolCall {
+    pub name: String,
+    #[serde(default)]
+    pub input: Value,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct MockError {
+    pub status: u16,
+    /// Defaults to the Anthropic error type for the status.
+    #[serde(default, rename = "type")]
+    pub error_type: Option<String>,
+    #[serde(default)]
+    pub message: Option<String>,
+}
+
+/// One scripted reply. A step with neither `text` nor `tool_call` echoes the
+/// last user message.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct MockStep {
+    pub text: Option<String>,
+    pub tool_call: Option<MockToolCall>,
+    pub error: Option<MockError>,
+    pub latency_ms: Option<u64>,
+}
+
+#[derive(Debug, Default)]
+pub struct MockUpstream {
+    /// Replies in order, starting over after the last one.
+    pub script: Vec<MockStep>,
+    /// Delay before every response.
+    pub latency: Duration,
+    /// Delay between streamed events.
+    pub chunk_delay: Duration,
+    next: AtomicUsize,
+    served: AtomicUsize,
+}
+
+impl MockUpstream {
+    pub fn new(script: Vec<MockStep>, latency: Duration, chunk_delay: Duration) -> Self {
+        Self {
+            script,
+            latency,
+            chunk_delay,
+            ..Default::default()
+        }
+    }
+
+    /// Read a script: a JSON array of steps.
+    pub fn load_script(path: &Path) -> Result<Vec<MockStep>> {
+        let content = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read mock script {}", path.display()))?;
+        serde_json::from_str(&content)
+            .with_context(|| format!("Invalid mock script {}", path.display()))
+    }
+
+    /// The step for the next request, with per-request header overrides.
+    fn next_step(&self, headers: &HeaderMap) -> MockStep {
+        let mut step = if self.script.is_empty() {
+            MockStep::default()
+        } else {
+            let i = self.next.fetch_add(1, Ordering::Relaxed);
+            self.script[i % self.script.len()].clone()
+        };
+        let header = |name: &str| headers.get(name).and_then(|v| v.to_str().ok());
+        if let Some(status) = header(ERROR_HEADER).and_then(|v| v.trim().parse().ok()) {
+            step.error = Some(MockError {
+                status,
+                error_type: None,
+                message: None,
+            });
+        }
+        if let Some(ms) = header(LATENCY_HEADER).and_then(|v| v.trim().parse().ok()) {
+            step.latency_ms = Some(ms);
+        }
+        step
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Api {
+    Anthropic,
+    OpenAi,
+}
+
+/// What the mock says back, after resolving echo mode.
+struct Reply {
+    id: String,
+    model: String,
+    text: Option<String>,
+    tool_call: Option<MockToolCall>,
+    input_tokens: u64,
+    output_tokens: u64,
+}
+
+fn error_type_for(status: u16) -> &'static str {
+    match status {
+        400 => "invalid_request_error",
+        401 => "authentication_error",
+        403 => "permission_error",
+        404 => "not_found_error",
+        429 => "rate_limit_error",
+        529 => "overloaded_error",
+        _ => "api_error",
+    }
+}
+
+/// Text of the last user message, in either wire format.
+fn last_user_text(request: &Value) -> String {
+    let Some(message) = request["messages"]
+        .as_array()
+        .and_then(|messages| messages.iter().rev().find(|m| m["role"] == "user"))
+    else {
+        return String::new();
+    };
+    match &message["content"] {
+        Value::String(text) => text.clone(),
+        Value::Array(parts) => parts
+            .iter()
+            .filter_map(|part| part["text"].as_str())
+            .collect::<Vec<_>>()
+            .join(" "),
+        _ => String::new(),
+    }
+}
+
+/// A rough count, so usage-driven features have something to work with.
+fn estimate_tokens(text: &str) -> u64 {
+    (text.len() as u64).div_ceil(4).max(1)
+}
+
+fn reply(step: MockStep, request: &Value, serial: usize) -> Reply {
+    let text = match (&step.text, &step.tool_call) {
+        (None, None) => Some(format!("echo: {}", last_user_text(request))),
+        (text, _) => text.clone(),
+    };
+    let output = text.clone().unwrap_or_default()
+        + &step
+            .tool_call
+            .as_ref()
+            .map(|call| call.input.to_string())
+            .unwrap_or_default();
+    Reply {
+        id: format!("mock_{}", serial),
+        model: request["model"].as_str().unwrap_or("mock").to_string(),
+        text,
+        tool_call: step.tool_call,
+        input_tokens: estimate_tokens(&request["messages"].to_string()),
+        output_tokens: estimate_tokens(&output),
+    }
+}
+
+/// Split text into word-sized deltas, keeping the spaces.
+fn deltas(text: &str) -> Vec<&str> {
+    text.split_inclusive(' ').collect()
+}
+
+fn anthropic_message(reply: &Reply) -> Value {
+    let mut content = Vec::new();
+    if let Some(text) = &reply.text {
+        content.push(json!({"type": "text", "text": text}));
+    }
+    if let Some(call) = &reply.tool_call {
+        content.push(json!({
+            "type": "tool_use",
+            "id": format!("toolu_{}", reply.id),
+            "name": call.name,
+            "input": call.input,
+        }));
+    }
+    json!({
+        "id": format!("msg_{}", reply.id),
+        "type": "message",
+        "role": "assistant",
+        "model": reply.model,
+        "content": content,
+        "stop_reason": if reply.tool_call.is_some() { "tool_use" } else { "end_turn" },
+        "stop_sequence": null,
+        "usage": {"input_tokens": reply.input_tokens, "output_tokens": reply.output_tokens},
+    })
+}
+
+fn anthropic_events(reply: &Reply) -> Vec<Bytes> {
+    let event = |data: Value| {
+        Bytes::from(format!(
+            "event: {}\ndata: {}\n\n",
+            data["type"].as_str().unwrap_or_default(),
+            data
+        ))
+    };
+    let mut start = anthropic_message(reply);
+    start["content"] = json!([]);
+    start["stop_reason"] = Value::Null;
+    start["usage"]["output_tokens"] = json!(0);
+
+    let mut events = vec![event(json!({"type": "message_start", "message": start}))];
+    let mut index = 0;
+    if let Some(text) = &reply.text {
+        events.push(event(json!({
+            "type": "content_block_start",
+            "index": index,
+            "content_block": {"type": "text", "text": ""},
+        })));
+        for delta in deltas(text) {
+            events.push(event(json!({
+                "type": "content_block_delta",
+                "index": index,
+                "delta": {"type": "text_delta", "text": delta},
+            })));
+        }
+        events.push(event(json!({"type": "content_block_stop", "index": index})));
+        index += 1;
+    }
+    if let Some(call) = &reply.tool_call {
+        events.push(event(json!({
+            "type": "content_block_start",
+            "index": index,
+            "content_block": {
+                "type": "tool_use",
+                "id": format!("toolu_{}", reply.id),
+                "name": call.name,
+                "input": {},
+            },
+        })));
+        events.push(event(json!({
+            "type": "content_block_delta",
+            "index": index,
+            "delta": {"type": "input_json_delta", "partial_json": call.input.to_string()},
+        })));
+        events.push(event(json!({"type": "content_block_stop", "index": index})));
+    }
+    events.push(event(json!({
+        "type": "message_delta",
+        "delta": {
+            "stop_reason": if reply.tool_call.is_some() { "tool_use" } else { "end_turn" },
+            "stop_sequence": null,
+        },
+        "usage": {"output_tokens": reply.output_tokens},
+    })));
+    events.push(event(json!({"type": "message_stop"})));
+    events
+}
+
+fn openai_usage(reply: &Reply) -> Value {
+    json!({
+        "prompt_tokens": reply.input_tokens,
+        "completion_tokens": reply.output_tokens,
+        "total_tokens": reply.input_tokens + reply.output_tokens,
+    })
+}
+
+fn openai_tool_call(reply: &Reply, call: &MockToolCall) -> Value {
+    json!({
+        "id": format!("call_{}", reply.id),
+        "type": "function",
+        "function": {"name": call.name, "arguments": call.input.to_string()},
+    })
+}
+
+fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+fn openai_completion(reply: &Reply) -> Value {
+    let mut message = json!({"role": "assistant", "content": reply.text});
+    if let Some(call) = &reply.tool_call {
+        message["tool_calls"] = json!([openai_tool_call(reply, call)]);
+    }
+    json!({
+        "id": format!("chatcmpl-{}", reply.id),
+        "object": "chat.completion",
+        "created": unix_time(),
+        "model": reply.model,
+        "choices": [{
+            "index": 0,
+            "message": message,
+            "finish_reason": if reply.tool_call.is_some() { "tool_calls" } else { "stop" },
+        }],
+        "usage": openai_usage(reply),
+    })
+}
+
+fn openai_chunks(reply: &Reply, include_usage: bool) -> Vec<Bytes> {
+    let created = unix_time();
+    let chunk = |choices: Value| {
+        json!({
+            "id": format!("chatcmpl-{}", reply.id),
+            "object": "chat.completion.chunk",
+            "created": created,
+            "model": reply.model,
+            "choices": choices,
+        })
+    };
+    let mut chunks = vec![chunk(json!([
+        {"index": 0, "delta": {"role": "assistant", "content": ""}}
+    ]))];
+    if let Some(text) = &reply.text {
+        for delta in deltas(text) {
+            chunks.push(chunk(json!([{"index": 0, "delta": {"content": delta}}])));
+        }
+    }
+    if let Some(call) = &reply.tool_call {
+        let mut opening = openai_tool_call(reply, call);
+        opening["index"] = json!(0);
+        opening["function"]["arguments"] = json!("");
+        chunks.push(chunk(
+            json!([{"index": 0, "delta": {"tool_calls": [opening]}}]),
+        ));
+        chunks.push(chunk(json!([{"index": 0, "delta": {"tool_calls": [
+            {"index": 0, "function": {"arguments": call.input.to_string()}}
+        ]}}])));
+    }
+    chunks.push(chunk(json!([{
+        "index": 0,
+        "delta": {},
+        "finish_reason": if reply.tool_call.is_some() { "tool_calls" } else { "stop" },
+    }])));
+    // With `include_usage`, usage arrives in a final chunk with no choices.
+    if include_usage {
+        let mut last = chunk(json!([]));
+        last["usage"] = openai_usage(reply);
+        chunks.push(last);
+    }
+    let mut events: Vec<Bytes> = chunks
+        .into_iter()
+        .map(|data| Bytes::from(format!("data: {}\n\n", data)))
+        .collect();
+    events.push(Bytes::from_static(b"data: [DONE]\n\n"));
+    events
+}
+
+fn error_reply(api: Api, error: &MockError) -> Response<ProxyBody> {
+    let error_type = error
+        .error_type
+        .clone()
+        .unwrap_or_else(|| error_type_for(error.status).to_string());
+    let message = error
+        .message
+        .clone()
+        .unwrap_or_else(|| format!("mock-upstream injected a {} error", error.status));
+    match api {
+        Api::Anthropic => error_response(error.status, &error_type, &message),
+        Api::OpenAi => json_response(
+            error.status,
+            &json!({"error": {"message": message, "type": error_type, "code": null}}),
+        ),
+    }
+}
+
+fn event_stream(events: Vec<Bytes>, delay: Duration) -> Response<ProxyBody> {
+    let stream = futures_util::stream::iter(events).then(move |event| async move {
+        if !delay.is_zero() {
+            tokio::time::sleep(delay).await;
+        }
+        Ok::<_, BoxError>(Frame::data(event))
+    });
+    Response::builder()
+        .status(200)
+        .header("content-type", "text/event-stream")
+        .header("cache-control", "no-cache")
+        .body(StreamBody::new(stream).boxed_unsync())
+        .unwrap()
+}
+
+async fn handle(req: Request<Incoming>, mock: Arc<MockUpstream>) -> Result<Response<ProxyBody>> {
+    let method = req.method().clone();
+    let path = req.uri().path().to_string();
+    eprintln!("[mock] {} {}", method, path);
+
+    let api = match (&method, path.as_str()) {
+        (&Method::GET, p) if p.ends_with("/models") => {
+            return Ok(json_response(
+                200,
+                &json!({
+                    "data": [{"type": "model", "id": "mock", "display_name": "Mock", "object": "model"}],
+                    "has_more": false,
+                    "object": "list",
+                }),
+            ));
+        }
+        (&Method::POST, p) if p.ends_with("/messages/count_tokens") => {
+            let body = req.into_body().collect().await?.to_bytes();
+            let request: Value = serde_json::from_slice(&body).unwrap_or(Value::Null);
+            let tokens = estimate_tokens(&request["messages"].to_string());
+            return Ok(json_response(200, &json!({"input_tokens": tokens})));
+        }
+        (&Method::POST, p) if p.ends_with("/messages") => Api::Anthropic,
+        (&Method::POST, p) if p.ends_with("/chat/completions") => Api::OpenAi,
+        _ => {
+            return Ok(error_response(
+                404,
+                "not_found_error",
+                &format!("mock-upstream does not serve {} {}", method, path),
+            ))
+        }
+    };
+
+    let step = mock.next_step(req.headers());
+    let body = req.into_body().collect().await?.to_bytes();
+    let request: Value = match serde_json::from_slice(&body) {
+        Ok(request) => request,
+        Err(e) => {
+            return Ok(error_reply(
+                api,
+                &MockError {
+                    status: 400,
+                    error_type: Some("invalid_request_error".to_string()),
+                    message: Some(format!("Request body is not JSON: {}", e)),
+                },
+            ))
+        }
+    };
+
+    let latency = step
+        .latency_ms
+        .map(Duration::from_millis)
+        .unwrap_or(mock.latency);
+    if !latency.is_zero() {
+        tokio::time::sleep(latency).await;
+    }
+    if let Some(error) = &step.error {
+        return Ok(error_reply(api, error));
+    }
+
+    let serial = mock.served.fetch_add(1, Ordering::Relaxed) + 1;
+    let reply = reply(step, &request, serial);
+    let stream = request["stream"].as_bool().unwrap_or(false);
+    Ok(match (api, stream) {
+        (Api::Anthropic, false) => json_response(200, &anthropic_message(&reply)),
+        (Api::Anthropic, true) => event_stream(anthropic_events(&reply), mock.chunk_delay),
+        (Api::OpenAi, false) => json_response(200, &openai_completion(&reply)),
+        (Api::OpenAi, true) => {
+            let include_usage = request
+                .pointer("/stream_options/include_usage")
+                .and_then(Value::as_bool)
+                .unwrap_or(false);
+            event_stream(openai_chunks(&reply, include_usage), mock.chunk_delay)
+        }
+    })
+}
+
+pub async fn run(port: u16, mock: MockUpstream) -> Result<()> {
+    let mock = Arc::new(mock);
+    let addr = SocketAddr::from(([127, 0, 0, 1], port));
+    let listener = TcpListener::bind(addr).await?;
+    println!("Mock upstream listening on http://127.0.0.1:{}", port);
+    println!("  Anthropic base_url: http://127.0.0.1:{}", port);
+    println!("  OpenAI base_url:    http://127.0.0.1:{}/v1", port);
+
+    loop {
+        let (stream, _) = listener.accept().await?;
+        let io = TokioIo::new(stream);
+        let mock = mock.clone();
+
+        tokio::spawn(async move {
+            if let Err(e) = http1::Builder::new()
+                .serve_connection(
+                    io,
+                    service_fn(move |req| {
+                        let mock = mock.clone();
+                        async move {
+                            Ok::<_, hyper::Error>(handle(req, mock).await.unwrap_or_else(|e| {
+                                error_response(500, "api_error", &format!("{:#}", e))
+                            }))
+                        }
+                    }),
+                )
+                .await
+            {
+                eprintln!("Connection error: {}", e);
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn text_of(events: &[Bytes]) -> String {
+        events
+            .iter()
+            .filter_map(|event| {
+                let data = std::str::from_utf8(event).ok()?.split("data: ").nth(1)?;
+                let data: Value = serde_json::from_str(data.trim()).ok()?;
+                data.pointer("/delta/text")
+                    .or(data.pointer("/choices/0/delta/content"))
+                    .and_then(Value::as_str)
+                    .map(str::to_string)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn echoes_the_last_user_message_in_either_format() {
+        let anthropic = json!({"model": "m", "messages": [
+            {"role": "user", "content": "first"},
+            {"role": "assistant", "content": "ok"},
+            {"role": "user", "content": [{"type": "text", "text": "second"}]},
+        ]});
+        let reply = reply(MockStep::default(), &anthropic, 1);
+        assert_eq!(reply.text.as_deref(), Some("echo: second"));
+        assert_eq!(text_of(&anthropic_events(&reply)), "echo: second");
+        assert_eq!(text_of(&openai_chunks(&reply, false)), "echo: second");
+    }
+
+    #[test]
+    fn script_cycles_and_headers_override() {
+        let mock = MockUpstream {
+            script: vec![
+                MockStep {
+                    text: Some("one".to_string()),
+                    ..Default::default()
+                },
+                MockStep {
+                    text: Some("two".to_string()),
+                    ..Default::default()
+                },
+            ],
+            ..Default::default()
+        };
+        let texts: Vec<_> = (0..3)
+            .map(|_| mock.next_step(&HeaderMap::new()).text.unwrap())
+            .collect();
+        assert_eq!(texts, ["one", "two", "one"]);
+
+        let mut headers = HeaderMap::new();
+        headers.insert(ERROR_HEADER, "529".parse().unwrap());
+        let step = mock.next_step(&headers);
+        assert_eq!(step.error.unwrap().status, 529);
+    }
+
+    #[test]
+    fn tool_calls_stream_in_both_formats() {
+        let step: MockStep = serde_json::from_value(json!({
+            "tool_call": {"name": "get_weather", "input": {"city": "Paris"}}
+        }))
+        .unwrap();
+        let reply = reply(step, &json!({"model": "m", "messages": []}), 1);
+        assert!(reply.text.is_none());
+        let message = anthropic_message(&reply);
+        assert_eq!(message["stop_reason"], "tool_use");
+        assert_eq!(message["content"][0]["input"]["city"], "Paris");
+
+        let chunks = openai_chunks(&reply, true);
+        let joined: String = chunks
+            .iter()
+            .map(|c| String::from_utf8_lossy(c).into_owned())
+            .collect();
+        assert!(joined.contains("\"name\":\"get_weather\""));
+        assert!(joined.contains("\"finish_reason\":\"tool_calls\""));
+        assert!(joined.contains("\"prompt_tokens\""));
+        assert!(joined.ends_with("data: [DONE]\n\n"));
+    }
+}
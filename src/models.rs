@@ -0,0 +1,170 @@
+//! `GET /v1/models` answered by the proxy: the tier aliases and models of a
+//! provider's mapping, optionally followed by the provider's own list.
+
+use serde_json::{json, Value};
+use std::time::{Duration, UNIX_EPOCH};
+
+use crate::config::Provider;
+use crate::rewrite::{classify_model, rewrite_model};
+use crate::time;
+
+/// The models this provider serves, in Anthropic's model format: one entry
+/// per tier alias Claude Code accepts (`haiku`, `sonnet`, `opus`), which tier
+/// rewriting maps, then each model the mapping names, which passes through
+/// unchanged. Providers without a mapping list nothing here.
+pub fn tier_models(provider: &Provider) -> Vec<Value> {
+    let Some(mapping) = &provider.models else {
+        return Vec::new();
+    };
+    let tiers = [
+        ("haiku", &mapping.haiku),
+        ("sonnet", &mapping.sonnet),
+        ("opus", &mapping.opus),
+    ];
+    let mut listed: Vec<Value> = tiers
+        .iter()
+        .map(|(alias, _)| tier_model(alias, provider))
+        .collect();
+    for (_, target) in tiers {
+        if !listed.iter().any(|m| m["id"] == target.as_str()) {
+            listed.push(model(target, target));
+        }
+    }
+    listed
+}
+
+/// A listed model, or any Claude ID the mapping rewrites.
+pub fn find(provider: &Provider, id: &str) -> Option<Value> {
+    let listed = tier_models(provider).into_iter().find(|m| m["id"] == id);
+    listed.or_else(|| {
+        (provider.models.is_some() && classify_model(id).is_some())
+            .then(|| tier_model(id, provider))
+    })
+}
+
+/// `id` labelled with its tier and the model it is rewritten to.
+fn tier_model(id: &str, provider: &Provider) -> Value {
+    let tier = classify_model(id).unwrap_or_default();
+    let name = format!("Claude {}{}", tier[..1].to_uppercase(), &tier[1..]);
+    model(id, &format!("{} ({})", name, rewrite_model(id, provider)))
+}
+
+fn model(id: &str, display_name: &str) -> Value {
+    json!({
+        "type": "model",
+        "id": id,
+        "display_name": display_name,
+        "created_at": Value::Null,
+    })
+}
+
+/// Models from an upstream list, in Anthropic or OpenAI format.
+pub fn upstream_models(body: &Value) -> Vec<Value> {
+    let entries = body.get("data").and_then(Value::as_array);
+    entries
+        .into_iter()
+        .flatten()
+        .filter_map(|entry| {
+            let id = entry.get("id")?.as_str()?;
+            let created_at = match entry.get("created_at") {
+                Some(created_at) => created_at.clone(),
+                None => entry
+                    .get("created")
+                    .and_then(Value::as_u64)
+                    .map(|secs| json!(time::format_rfc3339(UNIX_EPOCH + Duration::from_secs(secs))))
+                    .unwrap_or(Value::Null),
+            };
+            Some(json!({
+                "type": "model",
+                "id": id,
+                "display_name": entry.get("display_name").and_then(Value::as_str).unwrap_or(id),
+                "created_at": created_at,
+            }))
+        })
+        .collect()
+}
+
+/// `upstream` appended to `tiers`, skipping IDs already listed.
+pub fn merge(mut tiers: Vec<Value>, upstream: Vec<Value>) -> Vec<Value> {
+    for model in upstream {
+        if !tiers.iter().any(|m| m["id"] == model["id"]) {
+            tiers.push(model);
+        }
+    }
+    tiers
+}
+
+/// One page holding every model, as `GET /v1/models` returns it.
+pub fn page(models: Vec<Value>) -> Value {
+    json!({
+        "first_id": models.first().map(|m| m["id"].clone()),
+        "last_id": models.last().map(|m| m["id"].clone()),
+        "has_more": false,
+        "data": models,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::ModelMapping;
+
+    fn ids(models: &[Value]) -> Vec<&str> {
+        models.iter().map(|m| m["id"].as_str().unwrap()).collect()
+    }
+
+    fn glm() -> Provider {
+        Provider {
+            models: Some(ModelMapping {
+                haiku: "glm-4.5-air".into(),
+                sonnet: "glm-4.7".into(),
+                opus: "glm-4.7".into(),
+            }),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn lists_tiers_and_mapped_models() {
+        let listed = tier_models(&glm());
+        assert_eq!(
+            ids(&listed),
+            ["haiku", "sonnet", "opus", "glm-4.5-air", "glm-4.7"]
+        );
+        assert_eq!(listed[1]["display_name"], "Claude Sonnet (glm-4.7)");
+        assert_eq!(listed[0]["type"], "model");
+        // Every tier alias is rewritten to its mapped model.
+        for alias in ["haiku", "sonnet", "opus"] {
+            assert_ne!(rewrite_model(alias, &glm()), alias);
+        }
+        assert!(tier_models(&Provider::default()).is_empty());
+    }
+
+    #[test]
+    fn finds_any_rewritten_claude_id() {
+        let found = find(&glm(), "claude-opus-4-5-20251101").unwrap();
+        assert_eq!(found["id"], "claude-opus-4-5-20251101");
+        assert_eq!(found["display_name"], "Claude Opus (glm-4.7)");
+        assert_eq!(find(&glm(), "glm-4.5-air").unwrap()["id"], "glm-4.5-air");
+        assert!(find(&glm(), "gpt-5").is_none());
+        assert!(find(&Provider::default(), "claude-opus-4-5").is_none());
+    }
+
+    #[test]
+    fn merges_openai_lists_without_duplicates() {
+        let upstream = upstream_models(&json!({
+            "object": "list",
+            "data": [
+                {"id": "glm-4.7", "object": "model", "created": 1_750_000_000, "owned_by": "z-ai"},
+                {"id": "glm-5", "object": "model"}
+            ]
+        }));
+        assert_eq!(upstream[0]["created_at"], "2025-06-15T15:06:40.000Z");
+        let merged = merge(tier_models(&glm()), upstream);
+        assert_eq!(merged.len(), 6);
+        let listed = page(merged);
+        assert_eq!(listed["first_id"], "haiku");
+        assert_eq!(listed["last_id"], "glm-5");
+        assert_eq!(listed["has_more"], false);
+    }
+}
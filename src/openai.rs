@@ -0,0 +1,1089 @@
+//! Translation between the Anthropic Messages API and the OpenAI Chat
+//! Completions API, used for providers configured with `api_format: openai`.
+
+use serde_json::{json, Map, Value};
+
+/// Upstream path for Chat Completions, relative to the Anthropic `/v1` root.
+pub const CHAT_COMPLETIONS_PATH: &str = "/v1/chat/completions";
+
+/// Whether an inbound Anthropic path is the Messages endpoint we translate.
+pub fn is_messages_path(path: &str) -> bool {
+    path == "/v1/messages"
+}
+
+fn system_text(system: &Value) -> Option<String> {
+    match system {
+        Value::String(s) if !s.is_empty() => Some(s.clone()),
+        Value::Array(blocks) => {
+            let text = blocks
+                .iter()
+                .filter_map(|b| b.get("text").and_then(Value::as_str))
+                .collect::<Vec<_>>()
+                .join("\n\n");
+            if text.is_empty() {
+                None
+            } else {
+                Some(text)
+            }
+        }
+        _ => None,
+    }
+}
+
+fn image_part(block: &Value) -> Option<Value> {
+    let source = block.get("source")?;
+    let url = match source.get("type").and_then(Value::as_str) {
+        Some("base64") => format!(
+            "data:{};base64,{}",
+            source.get("media_type").and_then(Value::as_str)?,
+            source.get("data").and_then(Value::as_str)?
+        ),
+        Some("url") => source.get("url").and_then(Value::as_str)?.to_string(),
+        _ => return None,
+    };
+    Some(json!({"type": "image_url", "image_url": {"url": url}}))
+}
+
+/// Convert Anthropic content blocks into OpenAI content parts. Blocks with no
+/// Chat Completions equivalent (e.g. `thinking`) are dropped.
+fn content_parts(blocks: &[Value]) -> Vec<Value> {
+    blocks
+        .iter()
+        .filter_map(|block| match block.get("type").and_then(Value::as_str) {
+            Some("text") => Some(json!({
+                "type": "text",
+                "text": block.get("text").cloned().unwrap_or(Value::Null),
+            })),
+            Some("image") => image_part(block),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Collapse content parts into a plain string when they are all text, which
+/// is the form most OpenAI-compatible servers accept for every role.
+fn collapse_parts(parts: Vec<Value>) -> Value {
+    if parts
+        .iter()
+        .all(|p| p.get("type").and_then(Value::as_str) == Some("text"))
+    {
+        let text = parts
+            .iter()
+            .filter_map(|p| p.get("text").and_then(Value::as_str))
+            .collect::<Vec<_>>()
+            .join("");
+        Value::String(text)
+    } else {
+        Value::Array(parts)
+    }
+}
+
+/// Flatten a `tool_result` block's content into the plain string that a
+/// `role: tool` message carries. Images cannot ride along, so they are
+/// returned separately for a follow-up user message.
+fn tool_result_content(block: &Value) -> (String, Vec<Value>) {
+    let mut text = match block.get("content") {
+        Some(Value::String(s)) => s.clone(),
+        Some(Value::Array(items)) => items
+            .iter()
+            .filter_map(|i| i.get("text").and_then(Value::as_str))
+            .collect::<Vec<_>>()
+            .join("\n"),
+        _ => String::new(),
+    };
+    let images = match block.get("content") {
+        Some(Value::Array(items)) => items
+            .iter()
+            .filter(|i| i.get("type").and_then(Value::as_str) == Some("image"))
+            .filter_map(image_part)
+            .collect(),
+        _ => Vec::new(),
+    };
+    if block.get("is_error").and_then(Value::as_bool) == Some(true) {
+        text = format!("Error: {}", text);
+    }
+    (text, images)
+}
+
+fn tool_call(block: &Value) -> Option<Value> {
+    let input = block.get("input").cloned().unwrap_or_else(|| json!({}));
+    Some(json!({
+        "id": block.get("id")?,
+        "type": "function",
+        "function": {
+            "name": block.get("name")?,
+            "arguments": input.to_string(),
+        },
+    }))
+}
+
+fn convert_message(message: &Value, out: &mut Vec<Value>) {
+    let role = message
+        .get("role")
+        .and_then(Value::as_str)
+        .unwrap_or("user");
+    let blocks = match message.get("content") {
+        Some(Value::Array(blocks)) => blocks,
+        Some(content) => {
+            out.push(json!({"role": role, "content": content}));
+            return;
+        }
+        None => return,
+    };
+
+    let of_type = |ty: &'static str| {
+        blocks
+            .iter()
+            .filter(move |b| b.get("type").and_then(Value::as_str) == Some(ty))
+    };
+
+    if role == "assistant" {
+        let tool_calls: Vec<Value> = of_type("tool_use").filter_map(tool_call).collect();
+        let parts = content_parts(blocks);
+        if tool_calls.is_empty() && parts.is_empty() {
+            return;
+        }
+        let mut msg = Map::new();
+        msg.insert("role".into(), json!("assistant"));
+        msg.insert(
+            "content".into(),
+            if parts.is_empty() {
+                Value::Null
+            } else {
+                collapse_parts(parts)
+            },
+        );
+        if !tool_calls.is_empty() {
+            msg.insert("tool_calls".into(), Value::Array(tool_calls));
+        }
+        out.push(Value::Object(msg));
+        return;
+    }
+
+    // Tool results must directly follow the assistant turn that issued the
+    // calls, so they go out before any other user content in this message.
+    let mut parts = Vec::new();
+    for block in of_type("tool_result") {
+        let (text, images) = tool_result_content(block);
+        out.push(json!({
+            "role": "tool",
+            "tool_call_id": block.get("tool_use_id").cloned().unwrap_or(Value::Null),
+            "content": text,
+        }));
+        parts.extend(images);
+    }
+    parts.extend(content_parts(blocks));
+    if !parts.is_empty() {
+        out.push(json!({"role": role, "content": collapse_parts(parts)}));
+    }
+}
+
+fn convert_tools(tools: &[Value]) -> Vec<Value> {
+    tools
+        .iter()
+        .filter_map(|tool| {
+            let name = tool.get("name")?;
+            let mut function = Map::new();
+            function.insert("name".into(), name.clone());
+            if let Some(description) = tool.get("description") {
+                function.insert("description".into(), description.clone());
+            }
+            function.insert(
+                "parameters".into(),
+                tool.get("input_schema")
+                    .cloned()
+                    .unwrap_or_else(|| json!({"type": "object", "properties": {}})),
+            );
+            Some(json!({"type": "function", "function": function}))
+        })
+        .collect()
+}
+
+fn convert_tool_choice(choice: &Value) -> Option<Value> {
+    match choice.get("type").and_then(Value::as_str)? {
+        "auto" => Some(json!("auto")),
+        "any" => Some(json!("required")),
+        "none" => Some(json!("none")),
+        "tool" => Some(json!({
+            "type": "function",
+            "function": {"name": choice.get("name")?},
+        })),
+        _ => None,
+    }
+}
+
+/// Translate an Anthropic Messages request body into a Chat Completions
+/// request body. The `model` field is expected to be rewritten already.
+pub fn anthropic_to_openai_request(body: &Value) -> Value {
+    let mut out = Map::new();
+    if let Some(model) = body.get("model") {
+        out.insert("model".into(), model.clone());
+    }
+
+    let mut messages = Vec::new();
+    if let Some(text) = body.get("system").and_then(system_text) {
+        messages.push(json!({"role": "system", "content": text}));
+    }
+    if let Some(Value::Array(inbound)) = body.get("messages") {
+        for message in inbound {
+            convert_message(message, &mut messages);
+        }
+    }
+    out.insert("messages".into(), Value::Array(messages));
+
+    if let Some(max_tokens) = body.get("max_tokens") {
+        out.insert("max_tokens".into(), max_tokens.clone());
+    }
+    for key in ["temperature", "top_p"] {
+        if let Some(value) = body.get(key) {
+            out.insert(key.into(), value.clone());
+        }
+    }
+    if let Some(stop) = body.get("stop_sequences") {
+        out.insert("stop".into(), stop.clone());
+    }
+    if let Some(user) = body.pointer("/metadata/user_id") {
+        out.insert("user".into(), user.clone());
+    }
+    if body.get("stream").and_then(Value::as_bool) == Some(true) {
+        out.insert("stream".into(), Value::Bool(true));
+        out.insert("stream_options".into(), json!({"include_usage": true}));
+    }
+
+    if let Some(Value::Array(tools)) = body.get("tools") {
+        let tools = convert_tools(tools);
+        if !tools.is_empty() {
+            out.insert("tools".into(), Value::Array(tools));
+        }
+    }
+    if let Some(choice) = body.get("tool_choice") {
+        if let Some(converted) = convert_tool_choice(choice) {
+            out.insert("tool_choice".into(), converted);
+        }
+        if choice
+            .get("disable_parallel_tool_use")
+            .and_then(Value::as_bool)
+            == Some(true)
+        {
+            out.insert("parallel_tool_calls".into(), Value::Bool(false));
+        }
+    }
+
+    Value::Object(out)
+}
+
+/// Map an OpenAI `finish_reason` onto an Anthropic `stop_reason`.
+pub fn map_finish_reason(reason: &str) -> &'static str {
+    match reason {
+        "length" => "max_tokens",
+        "tool_calls" | "function_call" => "tool_use",
+        "content_filter" => "refusal",
+        _ => "end_turn",
+    }
+}
+
+fn map_usage(usage: Option<&Value>) -> Value {
+    let field = |name: &str| {
+        usage
+            .and_then(|u| u.get(name))
+            .and_then(Value::as_u64)
+            .unwrap_or(0)
+    };
+    json!({
+        "input_tokens": field("prompt_tokens"),
+        "output_tokens": field("completion_tokens"),
+    })
+}
+
+fn message_id(id: Option<&Value>) -> String {
+    match id.and_then(Value::as_str) {
+        Some(id) if id.starts_with("msg_") => id.to_string(),
+        Some(id) => format!("msg_{}", id),
+        None => "msg_proxy".to_string(),
+    }
+}
+
+/// Keep upstream tool call IDs verbatim so `tool_result` blocks sent back by
+/// the client match what the upstream issued. Servers that omit IDs get a
+/// deterministic one derived from the message ID and call position.
+fn tool_use_id(id: Option<&Value>, message_id: &str, index: usize) -> String {
+    match id.and_then(Value::as_str) {
+        Some(id) if !id.is_empty() => id.to_string(),
+        _ => format!("toolu_{}_{}", message_id.trim_start_matches("msg_"), index),
+    }
+}
+
+fn parse_arguments(arguments: &str) -> Value {
+    if arguments.trim().is_empty() {
+        return json!({});
+    }
+    serde_json::from_str(arguments).unwrap_or_else(|_| json!({}))
+}
+
+/// Translate a unary Chat Completions response into an Anthropic `message`.
+pub fn openai_to_anthropic_response(body: &Value) -> Value {
+    let choice = body.pointer("/choices/0");
+    let message = choice.and_then(|c| c.get("message"));
+
+    let id = message_id(body.get("id"));
+    let mut content = Vec::new();
+    if let Some(text) = message
+        .and_then(|m| m.get("content"))
+        .and_then(Value::as_str)
+    {
+        if !text.is_empty() {
+            content.push(json!({"type": "text", "text": text}));
+        }
+    }
+    if let Some(Value::Array(calls)) = message.and_then(|m| m.get("tool_calls")) {
+        for (i, call) in calls.iter().enumerate() {
+            let arguments = call
+                .pointer("/function/arguments")
+                .and_then(Value::as_str)
+                .unwrap_or_default();
+            content.push(json!({
+                "type": "tool_use",
+                "id": tool_use_id(call.get("id"), &id, i),
+                "name": call.pointer("/function/name").cloned().unwrap_or(Value::Null),
+                "input": parse_arguments(arguments),
+            }));
+        }
+    }
+
+    let stop_reason = choice
+        .and_then(|c| c.get("finish_reason"))
+        .and_then(Value::as_str)
+        .map(map_finish_reason)
+        .unwrap_or("end_turn");
+
+    json!({
+        "id": id,
+        "type": "message",
+        "role": "assistant",
+        "model": body.get("model").cloned().unwrap_or(Value::Null),
+        "content": content,
+        "stop_reason": stop_reason,
+        "stop_sequence": null,
+        "usage": map_usage(body.get("usage")),
+    })
+}
+
+/// Translate an OpenAI-style error body into the Anthropic error envelope.
+pub fn openai_to_anthropic_error(status: u16, body: &Value) -> Value {
+    let message = body
+        .pointer("/error/message")
+        .and_then(Value::as_str)
+        .or_else(|| body.get("message").and_then(Value::as_str))
+        .unwrap_or("Upstream request failed");
+    let error_type = match status {
+        400 | 422 => "invalid_request_error",
+        401 => "authentication_error",
+        403 => "permission_error",
+        404 => "not_found_error",
+        413 => "request_too_large",
+        429 => "rate_limit_error",
+        529 | 503 => "overloaded_error",
+        _ => "api_error",
+    };
+    json!({
+        "type": "error",
+        "error": {"type": error_type, "message": message},
+    })
+}
+
+pub fn sse_event(out: &mut Vec<u8>, event: &str, data: &Value) {
+    out.extend_from_slice(b"event: ");
+    out.extend_from_slice(event.as_bytes());
+    out.extend_from_slice(b"\ndata: ");
+    out.extend_from_slice(data.to_string().as_bytes());
+    out.extend_from_slice(b"\n\n");
+}
+
+fn block_stop(out: &mut Vec<u8>, index: usize) {
+    sse_event(
+        out,
+        "content_block_stop",
+        &json!({"type": "content_block_stop", "index": index}),
+    );
+}
+
+fn input_json_delta(out: &mut Vec<u8>, index: usize, partial_json: &str) {
+    sse_event(
+        out,
+        "content_block_delta",
+        &json!({
+            "type": "content_block_delta",
+            "index": index,
+            "delta": {"type": "input_json_delta", "partial_json": partial_json},
+        }),
+    );
+}
+
+/// Rebuilds the Anthropic streaming event sequence from `chat.completion.chunk`
+/// server-sent events. Feed raw upstream bytes to [`StreamTranslator::push`]
+/// and call [`StreamTranslator::finish`] once the upstream stream ends.
+#[derive(Default)]
+pub struct StreamTranslator {
+    buffer: Vec<u8>,
+    started: bool,
+    finished: bool,
+    message_id: String,
+    next_index: usize,
+    open_block: Option<OpenBlock>,
+    /// Parallel tool calls whose deltas arrived while another block was open,
+    /// in order of first appearance. Each is emitted whole once that block
+    /// closes.
+    pending_calls: Vec<PendingToolCall>,
+    stop_reason: Option<&'static str>,
+    usage: Option<Value>,
+}
+
+/// The content block currently open on the Anthropic side. Anthropic blocks
+/// are strictly sequential, so at most one is open at a time.
+#[derive(Clone, Copy, PartialEq)]
+enum OpenBlock {
+    Text {
+        index: usize,
+    },
+    /// `call` is the upstream `tool_calls[].index` feeding this block.
+    ToolUse {
+        index: usize,
+        call: u64,
+    },
+}
+
+impl OpenBlock {
+    fn index(self) -> usize {
+        match self {
+            OpenBlock::Text { index } | OpenBlock::ToolUse { index, .. } => index,
+        }
+    }
+}
+
+/// A streamed tool call being assembled from its `tool_calls[]` deltas.
+struct PendingToolCall {
+    /// The upstream `tool_calls[].index` feeding this call.
+    call: u64,
+    id: String,
+    name: Value,
+    arguments: String,
+}
+
+impl StreamTranslator {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Consume a chunk of upstream bytes and return any Anthropic SSE bytes
+    /// that can be emitted so far.
+    pub fn push(&mut self, chunk: &[u8]) -> Vec<u8> {
+        self.buffer.extend_from_slice(chunk);
+        let mut out = Vec::new();
+        while let Some(pos) = self.buffer.iter().position(|&b| b == b'\n') {
+            let line: Vec<u8> = self.buffer.drain(..=pos).collect();
+            let line = String::from_utf8_lossy(&line);
+            let line = line.trim();
+            let Some(data) = line.strip_prefix("data:") else {
+                continue;
+            };
+            let data = data.trim();
+            if data == "[DONE]" {
+                self.finish_into(&mut out);
+                continue;
+            }
+            if let Ok(chunk) = serde_json::from_str::<Value>(data) {
+                self.handle_chunk(&chunk, &mut out);
+            }
+        }
+        out
+    }
+
+    /// Flush the closing events. Safe to call more than once.
+    pub fn finish(&mut self) -> Vec<u8> {
+        let mut out = Vec::new();
+        if !self.buffer.is_empty() {
+            self.buffer.push(b'\n');
+            out = self.push(&[]);
+        }
+        self.finish_into(&mut out);
+        out
+    }
+
+    fn start(&mut self, chunk: &Value, out: &mut Vec<u8>) {
+        if self.started {
+            return;
+        }
+        self.started = true;
+        self.message_id = message_id(chunk.get("id"));
+        let message = json!({
+            "type": "message_start",
+            "message": {
+                "id": self.message_id,
+                "type": "message",
+                "role": "assistant",
+                "model": chunk.get("model").cloned().unwrap_or(Value::Null),
+                "content": [],
+                "stop_reason": null,
+                "stop_sequence": null,
+                "usage": {"input_tokens": 0, "output_tokens": 0},
+            },
+        });
+        sse_event(out, "message_start", &message);
+    }
+
+    /// Close the open block, then emit the tool calls buffered behind it.
+    fn close_block(&mut self, out: &mut Vec<u8>) {
+        if let Some(block) = self.open_block.take() {
+            block_stop(out, block.index());
+        }
+        for call in std::mem::take(&mut self.pending_calls) {
+            let index = self.start_tool_use(&call.id, &call.name, out);
+            if !call.arguments.is_empty() {
+                input_json_delta(out, index, &call.arguments);
+            }
+            block_stop(out, index);
+        }
+    }
+
+    fn start_tool_use(&mut self, id: &str, name: &Value, out: &mut Vec<u8>) -> usize {
+        let index = self.next_index;
+        self.next_index += 1;
+        sse_event(
+            out,
+            "content_block_start",
+            &json!({
+                "type": "content_block_start",
+                "index": index,
+                "content_block": {"type": "tool_use", "id": id, "name": name, "input": {}},
+            }),
+        );
+        index
+    }
+
+    fn text_delta(&mut self, text: &str, out: &mut Vec<u8>) {
+        let index = match self.open_block {
+            Some(OpenBlock::Text { index }) => index,
+            _ => {
+                self.close_block(out);
+                let index = self.next_index;
+                self.next_index += 1;
+                self.open_block = Some(OpenBlock::Text { index });
+                sse_event(
+                    out,
+                    "content_block_start",
+                    &json!({
+                        "type": "content_block_start",
+                        "index": index,
+                        "content_block": {"type": "text", "text": ""},
+                    }),
+                );
+                index
+            }
+        };
+        sse_event(
+            out,
+            "content_block_delta",
+            &json!({
+                "type": "content_block_delta",
+                "index": index,
+                "delta": {"type": "text_delta", "text": text},
+            }),
+        );
+    }
+
+    /// Handle one `tool_calls[]` delta. A call's first delta carries its ID
+    /// and name and opens a `tool_use` block; argument fragments then stream
+    /// through as `input_json_delta` events. Deltas for other calls that
+    /// arrive while a `tool_use` block is open are buffered until it closes.
+    fn tool_call_delta(&mut self, call: &Value, out: &mut Vec<u8>) {
+        let call_index = call.get("index").and_then(Value::as_u64).unwrap_or(0);
+        let fragment = call
+            .pointer("/function/arguments")
+            .and_then(Value::as_str)
+            .unwrap_or_default();
+        let index = match self.open_block {
+            Some(OpenBlock::ToolUse { index, call }) if call == call_index => index,
+            Some(OpenBlock::ToolUse { .. }) => {
+                let position = match self.pending_calls.iter().position(|c| c.call == call_index) {
+                    Some(position) => position,
+                    None => {
+                        self.pending_calls.push(PendingToolCall {
+                            call: call_index,
+                            id: tool_use_id(call.get("id"), &self.message_id, call_index as usize),
+                            name: Value::Null,
+                            arguments: String::new(),
+                        });
+                        self.pending_calls.len() - 1
+                    }
+                };
+                let pending = &mut self.pending_calls[position];
+                if let Some(name) = call.pointer("/function/name").filter(|n| n.is_string()) {
+                    if pending.name.is_null() {
+                        pending.name = name.clone();
+                    }
+                }
+                pending.arguments.push_str(fragment);
+                return;
+            }
+            _ => {
+                self.close_block(out);
+                let id = tool_use_id(call.get("id"), &self.message_id, call_index as usize);
+                let name = call
+                    .pointer("/function/name")
+                    .cloned()
+                    .unwrap_or(Value::Null);
+                let index = self.start_tool_use(&id, &name, out);
+                self.open_block = Some(OpenBlock::ToolUse {
+                    index,
+                    call: call_index,
+                });
+                index
+            }
+        };
+        if !fragment.is_empty() {
+            input_json_delta(out, index, fragment);
+        }
+    }
+
+    fn handle_chunk(&mut self, chunk: &Value, out: &mut Vec<u8>) {
+        if self.finished {
+            return;
+        }
+        self.start(chunk, out);
+        if let Some(usage) = chunk.get("usage").filter(|u| u.is_object()) {
+            self.usage = Some(usage.clone());
+        }
+        let Some(choice) = chunk.pointer("/choices/0") else {
+            return;
+        };
+        if let Some(text) = choice
+            .pointer("/delta/content")
+            .and_then(Value::as_str)
+            .filter(|t| !t.is_empty())
+        {
+            self.text_delta(text, out);
+        }
+        if let Some(Value::Array(calls)) = choice.pointer("/delta/tool_calls") {
+            for call in calls {
+                self.tool_call_delta(call, out);
+            }
+        }
+        if let Some(reason) = choice.get("finish_reason").and_then(Value::as_str) {
+            self.stop_reason = Some(map_finish_reason(reason));
+        }
+    }
+
+    fn finish_into(&mut self, out: &mut Vec<u8>) {
+        if self.finished {
+            return;
+        }
+        self.finished = true;
+        self.start(&Value::Null, out);
+        self.close_block(out);
+        // Chat Completions only reports usage at the end of the stream, so
+        // input tokens ride along on `message_delta` rather than `message_start`.
+        let usage = map_usage(self.usage.as_ref());
+        sse_event(
+            out,
+            "message_delta",
+            &json!({
+                "type": "message_delta",
+                "delta": {
+                    "stop_reason": self.stop_reason.unwrap_or("end_turn"),
+                    "stop_sequence": null,
+                },
+                "usage": usage,
+            }),
+        );
+        sse_event(out, "message_stop", &json!({"type": "message_stop"}));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_events(bytes: &[u8]) -> Vec<(String, Value)> {
+        let text = String::from_utf8(bytes.to_vec()).unwrap();
+        text.split("\n\n")
+            .filter(|e| !e.is_empty())
+            .map(|event| {
+                let mut lines = event.lines();
+                let name = lines.next().unwrap().strip_prefix("event: ").unwrap();
+                let data = lines.next().unwrap().strip_prefix("data: ").unwrap();
+                (name.to_string(), serde_json::from_str(data).unwrap())
+            })
+            .collect()
+    }
+
+    #[test]
+    fn system_blocks_become_system_message() {
+        let body = json!({
+            "model": "glm-4.7",
+            "system": [{"type": "text", "text": "Be terse."}, {"type": "text", "text": "Use Rust."}],
+            "messages": [{"role": "user", "content": "hi"}],
+            "max_tokens": 100,
+        });
+        let out = anthropic_to_openai_request(&body);
+        assert_eq!(out["messages"][0]["role"], "system");
+        assert_eq!(out["messages"][0]["content"], "Be terse.\n\nUse Rust.");
+        assert_eq!(out["messages"][1], json!({"role": "user", "content": "hi"}));
+        assert_eq!(out["max_tokens"], 100);
+    }
+
+    #[test]
+    fn content_arrays_are_collapsed_or_converted() {
+        let body = json!({
+            "messages": [
+                {"role": "user", "content": [{"type": "text", "text": "a"}, {"type": "text", "text": "b"}]},
+                {"role": "assistant", "content": [{"type": "thinking", "thinking": "hmm"}, {"type": "text", "text": "ok"}]},
+                {"role": "user", "content": [
+                    {"type": "text", "text": "look"},
+                    {"type": "image", "source": {"type": "base64", "media_type": "image/png", "data": "AAA"}}
+                ]},
+            ],
+        });
+        let out = anthropic_to_openai_request(&body);
+        assert_eq!(out["messages"][0]["content"], "ab");
+        assert_eq!(out["messages"][1]["content"], "ok");
+        assert_eq!(
+            out["messages"][2]["content"][1]["image_url"]["url"],
+            "data:image/png;base64,AAA"
+        );
+    }
+
+    #[test]
+    fn sampling_and_metadata_fields_are_mapped() {
+        let body = json!({
+            "messages": [],
+            "stop_sequences": ["END"],
+            "metadata": {"user_id": "u-1"},
+            "temperature": 0.2,
+            "top_k": 5,
+            "stream": true,
+        });
+        let out = anthropic_to_openai_request(&body);
+        assert_eq!(out["stop"], json!(["END"]));
+        assert_eq!(out["user"], "u-1");
+        assert_eq!(out["temperature"], 0.2);
+        assert!(out.get("top_k").is_none());
+        assert_eq!(out["stream_options"]["include_usage"], true);
+    }
+
+    #[test]
+    fn tools_and_tool_choice_are_mapped() {
+        let body = json!({
+            "messages": [],
+            "tools": [{
+                "name": "read_file",
+                "description": "Read a file",
+                "input_schema": {"type": "object", "properties": {"path": {"type": "string"}}}
+            }],
+            "tool_choice": {"type": "tool", "name": "read_file", "disable_parallel_tool_use": true},
+        });
+        let out = anthropic_to_openai_request(&body);
+        assert_eq!(out["tools"][0]["type"], "function");
+        assert_eq!(out["tools"][0]["function"]["name"], "read_file");
+        assert_eq!(
+            out["tools"][0]["function"]["parameters"]["properties"]["path"]["type"],
+            "string"
+        );
+        assert_eq!(out["tool_choice"]["function"]["name"], "read_file");
+        assert_eq!(out["parallel_tool_calls"], false);
+
+        let any = anthropic_to_openai_request(&json!({"tool_choice": {"type": "any"}}));
+        assert_eq!(any["tool_choice"], "required");
+    }
+
+    #[test]
+    fn unary_response_is_translated() {
+        let body = json!({
+            "id": "chatcmpl-1",
+            "object": "chat.completion",
+            "model": "glm-4.7",
+            "choices": [{"index": 0, "message": {"role": "assistant", "content": "Hello"}, "finish_reason": "length"}],
+            "usage": {"prompt_tokens": 12, "completion_tokens": 3},
+        });
+        let out = openai_to_anthropic_response(&body);
+        assert_eq!(out["id"], "msg_chatcmpl-1");
+        assert_eq!(out["type"], "message");
+        assert_eq!(out["content"][0], json!({"type": "text", "text": "Hello"}));
+        assert_eq!(out["stop_reason"], "max_tokens");
+        assert_eq!(
+            out["usage"],
+            json!({"input_tokens": 12, "output_tokens": 3})
+        );
+    }
+
+    #[test]
+    fn error_body_is_wrapped() {
+        let out = openai_to_anthropic_error(
+            429,
+            &json!({"error": {"message": "slow down", "type": "rate_limit"}}),
+        );
+        assert_eq!(out["type"], "error");
+        assert_eq!(out["error"]["type"], "rate_limit_error");
+        assert_eq!(out["error"]["message"], "slow down");
+    }
+
+    #[test]
+    fn stream_chunks_become_anthropic_events() {
+        let mut translator = StreamTranslator::new();
+        let mut out = Vec::new();
+        // Split mid-line to exercise buffering.
+        out.extend(translator.push(
+            b"data: {\"id\":\"c1\",\"model\":\"m\",\"choices\":[{\"delta\":{\"role\":\"assistant\",\"content\":\"He\"}}]}\n\ndata: {\"choices\":[{\"delta\":{\"con",
+        ));
+        out.extend(translator.push(
+            b"tent\":\"llo\"},\"finish_reason\":\"stop\"}]}\n\ndata: {\"choices\":[],\"usage\":{\"prompt_tokens\":5,\"completion_tokens\":2}}\n\ndata: [DONE]\n\n",
+        ));
+        out.extend(translator.finish());
+
+        let events = parse_events(&out);
+        let names: Vec<&str> = events.iter().map(|(n, _)| n.as_str()).collect();
+        assert_eq!(
+            names,
+            [
+                "message_start",
+                "content_block_start",
+                "content_block_delta",
+                "content_block_delta",
+                "content_block_stop",
+                "message_delta",
+                "message_stop"
+            ]
+        );
+        assert_eq!(events[0].1["message"]["id"], "msg_c1");
+        assert_eq!(events[3].1["delta"]["text"], "llo");
+        assert_eq!(events[5].1["delta"]["stop_reason"], "end_turn");
+        assert_eq!(events[5].1["usage"]["output_tokens"], 2);
+    }
+
+    #[test]
+    fn stream_without_done_is_closed_on_finish() {
+        let mut translator = StreamTranslator::new();
+        let mut out = translator.push(b"data: {\"choices\":[{\"delta\":{\"content\":\"x\"}}]}");
+        assert!(out.is_empty());
+        out.extend(translator.finish());
+        let events = parse_events(&out);
+        assert_eq!(events.last().unwrap().0, "message_stop");
+        assert_eq!(events[2].1["delta"]["text"], "x");
+    }
+
+    #[test]
+    fn tool_use_and_results_become_tool_calls_and_tool_messages() {
+        let body = json!({
+            "messages": [
+                {"role": "user", "content": "read it"},
+                {"role": "assistant", "content": [
+                    {"type": "text", "text": "Reading."},
+                    {"type": "tool_use", "id": "toolu_01", "name": "read_file", "input": {"path": "a.rs"}}
+                ]},
+                {"role": "user", "content": [
+                    {"type": "tool_result", "tool_use_id": "toolu_01", "content": [{"type": "text", "text": "fn main() {}"}]},
+                    {"type": "text", "text": "now explain"}
+                ]},
+            ],
+        });
+        let out = anthropic_to_openai_request(&body);
+        let messages = out["messages"].as_array().unwrap();
+        assert_eq!(messages.len(), 4);
+        assert_eq!(messages[1]["content"], "Reading.");
+        assert_eq!(messages[1]["tool_calls"][0]["id"], "toolu_01");
+        assert_eq!(
+            messages[1]["tool_calls"][0]["function"]["arguments"],
+            r#"{"path":"a.rs"}"#
+        );
+        assert_eq!(
+            messages[2],
+            json!({"role": "tool", "tool_call_id": "toolu_01", "content": "fn main() {}"})
+        );
+        assert_eq!(
+            messages[3],
+            json!({"role": "user", "content": "now explain"})
+        );
+    }
+
+    #[test]
+    fn assistant_with_only_tool_use_has_null_content() {
+        let body = json!({
+            "messages": [{"role": "assistant", "content": [
+                {"type": "tool_use", "id": "call_9", "name": "ls", "input": {}}
+            ]}],
+        });
+        let out = anthropic_to_openai_request(&body);
+        assert_eq!(out["messages"][0]["content"], Value::Null);
+        assert_eq!(
+            out["messages"][0]["tool_calls"][0]["function"]["arguments"],
+            "{}"
+        );
+    }
+
+    #[test]
+    fn error_tool_results_are_marked() {
+        let body = json!({
+            "messages": [{"role": "user", "content": [
+                {"type": "tool_result", "tool_use_id": "t", "content": "boom", "is_error": true}
+            ]}],
+        });
+        let out = anthropic_to_openai_request(&body);
+        assert_eq!(out["messages"][0]["content"], "Error: boom");
+    }
+
+    #[test]
+    fn unary_tool_calls_become_tool_use_blocks() {
+        let body = json!({
+            "id": "c2",
+            "choices": [{"message": {"role": "assistant", "content": null, "tool_calls": [
+                {"id": "call_abc", "type": "function", "function": {"name": "ls", "arguments": "{\"dir\":\".\"}"}},
+                {"type": "function", "function": {"name": "pwd", "arguments": ""}}
+            ]}, "finish_reason": "tool_calls"}],
+        });
+        let out = openai_to_anthropic_response(&body);
+        assert_eq!(out["stop_reason"], "tool_use");
+        assert_eq!(
+            out["content"][0],
+            json!({"type": "tool_use", "id": "call_abc", "name": "ls", "input": {"dir": "."}})
+        );
+        assert_eq!(out["content"][1]["id"], "toolu_c2_1");
+        assert_eq!(out["content"][1]["input"], json!({}));
+    }
+
+    #[test]
+    fn streamed_tool_calls_become_input_json_deltas() {
+        let mut translator = StreamTranslator::new();
+        let chunks = [
+            json!({"id": "c3", "choices": [{"delta": {"content": "Let me look."}}]}),
+            json!({"choices": [{"delta": {"tool_calls": [{"index": 0, "id": "call_1", "type": "function", "function": {"name": "ls", "arguments": ""}}]}}]}),
+            json!({"choices": [{"delta": {"tool_calls": [{"index": 0, "function": {"arguments": "{\"dir\":"}}]}}]}),
+            json!({"choices": [{"delta": {"tool_calls": [{"index": 0, "function": {"arguments": "\".\"}"}}]}}]}),
+            json!({"choices": [{"delta": {"tool_calls": [{"index": 1, "id": "call_2", "function": {"name": "pwd", "arguments": "{}"}}]}}]}),
+            json!({"choices": [{"delta": {}, "finish_reason": "tool_calls"}]}),
+        ];
+        let mut out = Vec::new();
+        for chunk in chunks {
+            out.extend(translator.push(format!("data: {}\n\n", chunk).as_bytes()));
+        }
+        out.extend(translator.push(b"data: [DONE]\n\n"));
+
+        let events = parse_events(&out);
+        let starts: Vec<&Value> = events
+            .iter()
+            .filter(|(n, _)| n == "content_block_start")
+            .map(|(_, d)| d)
+            .collect();
+        assert_eq!(starts.len(), 3);
+        assert_eq!(starts[1]["index"], 1);
+        assert_eq!(starts[1]["content_block"]["type"], "tool_use");
+        assert_eq!(starts[1]["content_block"]["id"], "call_1");
+        assert_eq!(starts[2]["content_block"]["id"], "call_2");
+
+        let partial: String = events
+            .iter()
+            .filter(|(_, d)| d["index"] == 1 && d["delta"]["type"] == "input_json_delta")
+            .map(|(_, d)| d["delta"]["partial_json"].as_str().unwrap().to_string())
+            .collect();
+        assert_eq!(partial, r#"{"dir":"."}"#);
+
+        let stops = events
+            .iter()
+            .filter(|(n, _)| n == "content_block_stop")
+            .count();
+        assert_eq!(stops, 3);
+        let delta = events.iter().find(|(n, _)| n == "message_delta").unwrap();
+        assert_eq!(delta.1["delta"]["stop_reason"], "tool_use");
+    }
+
+    #[test]
+    fn interleaved_parallel_tool_calls_stay_separate() {
+        let mut translator = StreamTranslator::new();
+        let chunks = [
+            json!({"id": "c4", "choices": [{"delta": {"tool_calls": [
+                {"index": 0, "id": "call_a", "function": {"name": "read", "arguments": ""}},
+                {"index": 1, "id": "call_b", "function": {"name": "grep", "arguments": ""}}
+            ]}}]}),
+            json!({"choices": [{"delta": {"tool_calls": [{"index": 0, "function": {"arguments": "{\"path\":"}}]}}]}),
+            json!({"choices": [{"delta": {"tool_calls": [{"index": 1, "function": {"arguments": "{\"pattern\":"}}]}}]}),
+            json!({"choices": [{"delta": {"tool_calls": [{"index": 0, "function": {"arguments": "\"a.rs\"}"}}]}}]}),
+            json!({"choices": [{"delta": {"tool_calls": [{"index": 1, "function": {"arguments": "\"fn\"}"}}]}}]}),
+            json!({"choices": [{"delta": {}, "finish_reason": "tool_calls"}]}),
+        ];
+        let mut out = Vec::new();
+        for chunk in chunks {
+            out.extend(translator.push(format!("data: {}\n\n", chunk).as_bytes()));
+        }
+        out.extend(translator.push(b"data: [DONE]\n\n"));
+
+        let events = parse_events(&out);
+        let starts: Vec<&Value> = events
+            .iter()
+            .filter(|(n, _)| n == "content_block_start")
+            .map(|(_, d)| d)
+            .collect();
+        assert_eq!(starts.len(), 2);
+        assert_eq!(starts[0]["content_block"]["id"], "call_a");
+        assert_eq!(starts[1]["content_block"]["id"], "call_b");
+
+        // Blocks never overlap, and each one's fragments join into its own
+        // complete arguments.
+        let mut open = None;
+        let mut arguments = [String::new(), String::new()];
+        for (name, data) in &events {
+            match name.as_str() {
+                "content_block_start" => assert_eq!(open.replace(data["index"].clone()), None),
+                "content_block_stop" => assert_eq!(open.take(), Some(data["index"].clone())),
+                "content_block_delta" => {
+                    assert_eq!(open, Some(data["index"].clone()));
+                    let index = data["index"].as_u64().unwrap() as usize;
+                    arguments[index].push_str(data["delta"]["partial_json"].as_str().unwrap());
+                }
+                _ => {}
+            }
+        }
+        let inputs: Vec<Value> = arguments
+            .iter()
+            .map(|a| serde_json::from_str(a).unwrap())
+            .collect();
+        assert_eq!(inputs, [json!({"path": "a.rs"}), json!({"pattern": "fn"})]);
+    }
+
+    #[test]
+    fn single_tool_call_streams_its_arguments() {
+        let mut translator = StreamTranslator::new();
+        let mut out = translator.push(
+            format!(
+                "data: {}\n\n",
+                json!({"id": "c5", "choices": [{"delta": {"tool_calls": [
+                    {"index": 0, "id": "call_w", "function": {"name": "write", "arguments": ""}}
+                ]}}]})
+            )
+            .as_bytes(),
+        );
+        for fragment in [
+            "{\"path\":",
+            "\"a.rs\",",
+            "\"content\":",
+            "\"fn main() {}\"}",
+        ] {
+            let chunk = json!({"choices": [{"delta": {"tool_calls": [
+                {"index": 0, "function": {"arguments": fragment}}
+            ]}}]});
+            let events = translator.push(format!("data: {}\n\n", chunk).as_bytes());
+            // Each fragment is relayed as soon as it arrives.
+            assert!(!events.is_empty());
+            out.extend(events);
+        }
+        out.extend(translator.finish());
+
+        let events = parse_events(&out);
+        let stop = events
+            .iter()
+            .position(|(n, _)| n == "content_block_stop")
+            .unwrap();
+        let deltas = events[..stop]
+            .iter()
+            .filter(|(_, d)| d["delta"]["type"] == "input_json_delta")
+            .count();
+        assert_eq!(deltas, 4);
+    }
+}
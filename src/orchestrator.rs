@@ -210,7 +210,7 @@ pub fn cmd_orchestrate_start(
         config.provider(&role.provider)?;
     }
 
-    match daemon::start_daemon(port) {
+    match daemon::start_daemon(port, None) {
         Ok(()) => {}
         Err(e) => {
             let msg = format!("{:#}", e);
@@ -0,0 +1,276 @@
+//! HTTP clients for reaching providers, built from each provider's
+//! `timeouts` and `network` settings: outbound proxy, extra root CAs, mTLS
+//! client certificate and certificate pinning.
+
+use anyhow::{bail, Context, Result};
+use reqwest::Client;
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::client::WebPkiServerVerifier;
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName, UnixTime};
+use rustls::{DigitallySignedStruct, RootCertStore, SignatureScheme};
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::config::{parse_fingerprint, NetworkSettings, Provider};
+
+/// Everything a provider's client is built from. A cached client is reused
+/// across config reloads for as long as these stay the same.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ClientSettings {
+    connect_timeout: Duration,
+    network: Option<NetworkSettings>,
+}
+
+impl ClientSettings {
+    pub fn of(provider: &Provider) -> Self {
+        Self {
+            connect_timeout: provider.timeouts.clone().unwrap_or_default().connect(),
+            network: provider.network.clone(),
+        }
+    }
+
+    pub fn build(&self) -> Result<Client> {
+        let mut builder = Client::builder().connect_timeout(self.connect_timeout);
+        let Some(network) = &self.network else {
+            return Ok(builder.build()?);
+        };
+        // Without an explicit proxy, reqwest still honours HTTPS_PROXY and
+        // friends from the environment.
+        if let Some(proxy) = network.http_proxy.as_ref().or(network.socks_proxy.as_ref()) {
+            builder =
+                builder.proxy(reqwest::Proxy::all(proxy).context("Invalid outbound proxy URL")?);
+        }
+        let custom_tls = !network.ca_files.is_empty()
+            || network.client_cert.is_some()
+            || !network.pin_sha256.is_empty();
+        if custom_tls {
+            builder = builder.use_preconfigured_tls(tls_config(network)?);
+        }
+        Ok(builder.build()?)
+    }
+}
+
+fn read_pem(path: &Path, what: &str) -> Result<Vec<u8>> {
+    std::fs::read(path).with_context(|| format!("Failed to read {} {}", what, path.display()))
+}
+
+/// Built-in roots plus `ca_files`, with the client certificate and pins
+/// applied. Mirrors reqwest's own rustls setup otherwise.
+fn tls_config(network: &NetworkSettings) -> Result<rustls::ClientConfig> {
+    let crypto = Arc::new(rustls::crypto::ring::default_provider());
+    let mut roots = RootCertStore {
+        roots: webpki_roots::TLS_SERVER_ROOTS.to_vec(),
+    };
+    for file in &network.ca_files {
+        let pem = read_pem(file, "CA file")?;
+        let certs = CertificateDer::pem_slice_iter(&pem)
+            .collect::<Result<Vec<_>, _>>()
+            .with_context(|| format!("Invalid PEM in CA file {}", file.display()))?;
+        if certs.is_empty() {
+            bail!("No certificates found in CA file {}", file.display());
+        }
+        for cert in certs {
+            roots
+                .add(cert)
+                .with_context(|| format!("Invalid certificate in CA file {}", file.display()))?;
+        }
+    }
+    let verifier =
+        WebPkiServerVerifier::builder_with_provider(Arc::new(roots), crypto.clone()).build()?;
+
+    let pins = network
+        .pin_sha256
+        .iter()
+        .map(|pin| parse_fingerprint(pin).with_context(|| format!("Invalid pin_sha256 '{}'", pin)))
+        .collect::<Result<Vec<_>>>()?;
+    let builder = rustls::ClientConfig::builder_with_provider(crypto)
+        .with_safe_default_protocol_versions()?;
+    let builder = if pins.is_empty() {
+        builder.with_webpki_verifier(verifier)
+    } else {
+        builder
+            .dangerous()
+            .with_custom_certificate_verifier(Arc::new(PinnedVerifier {
+                inner: verifier,
+                pins,
+            }))
+    };
+
+    let mut config = match (&network.client_cert, &network.client_key) {
+        (Some(cert), Some(key)) => {
+            let chain = CertificateDer::pem_slice_iter(&read_pem(cert, "client certificate")?)
+                .collect::<Result<Vec<_>, _>>()
+                .with_context(|| format!("Invalid PEM in client certificate {}", cert.display()))?;
+            let key = PrivateKeyDer::from_pem_slice(&read_pem(key, "client key")?)
+                .with_context(|| format!("Invalid PEM in client key {}", key.display()))?;
+            builder
+                .with_client_auth_cert(chain, key)
+                .context("Client certificate and key do not match")?
+        }
+        (None, None) => builder.with_no_client_auth(),
+        _ => bail!("mTLS needs both client_cert and client_key"),
+    };
+    // reqwest is built without HTTP/2.
+    config.alpn_protocols = vec![b"http/1.1".to_vec()];
+    Ok(config)
+}
+
+fn fingerprint_hex(der: &[u8]) -> String {
+    ring::digest::digest(&ring::digest::SHA256, der)
+        .as_ref()
+        .iter()
+        .map(|b| format!("{:02X}", b))
+        .collect::<Vec<_>>()
+        .join(":")
+}
+
+/// Normal chain verification, then the leaf must match one of the pins.
+#[derive(Debug)]
+struct PinnedVerifier {
+    inner: Arc<WebPkiServerVerifier>,
+    pins: Vec<[u8; 32]>,
+}
+
+impl ServerCertVerifier for PinnedVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        server_name: &ServerName<'_>,
+        ocsp_response: &[u8],
+        now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        self.inner.verify_server_cert(
+            end_entity,
+            intermediates,
+            server_name,
+            ocsp_response,
+            now,
+        )?;
+        let digest = ring::digest::digest(&ring::digest::SHA256, end_entity.as_ref());
+        if self.pins.iter().any(|pin| pin == digest.as_ref()) {
+            Ok(ServerCertVerified::assertion())
+        } else {
+            Err(rustls::Error::General(format!(
+                "certificate for {} does not match pin_sha256 (got {})",
+                server_name.to_str(),
+                fingerprint_hex(end_entity.as_ref())
+            )))
+        }
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.inner.verify_tls12_signature(message, cert, dss)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.inner.verify_tls13_signature(message, cert, dss)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.inner.supported_verify_schemes()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    const CA: &str = include_str!("../tests/fixtures/tls/ca.pem");
+    const LEAF: &str = include_str!("../tests/fixtures/tls/leaf.pem");
+
+    fn fixture(name: &str) -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("tests/fixtures/tls")
+            .join(name)
+    }
+
+    fn pinned(pins: Vec<[u8; 32]>, trust_fixture_ca: bool) -> PinnedVerifier {
+        let crypto = Arc::new(rustls::crypto::ring::default_provider());
+        let mut roots = RootCertStore {
+            roots: webpki_roots::TLS_SERVER_ROOTS.to_vec(),
+        };
+        if trust_fixture_ca {
+            roots
+                .add(CertificateDer::from_pem_slice(CA.as_bytes()).unwrap())
+                .unwrap();
+        }
+        PinnedVerifier {
+            inner: WebPkiServerVerifier::builder_with_provider(Arc::new(roots), crypto)
+                .build()
+                .unwrap(),
+            pins,
+        }
+    }
+
+    fn verify(verifier: &PinnedVerifier) -> Result<ServerCertVerified, rustls::Error> {
+        let leaf = CertificateDer::from_pem_slice(LEAF.as_bytes()).unwrap();
+        verifier.verify_server_cert(
+            &leaf,
+            &[],
+            &ServerName::try_from("localhost").unwrap(),
+            &[],
+            UnixTime::now(),
+        )
+    }
+
+    #[test]
+    fn pins_are_checked_after_the_chain() {
+        let leaf = CertificateDer::from_pem_slice(LEAF.as_bytes()).unwrap();
+        let pin = parse_fingerprint(&fingerprint_hex(leaf.as_ref())).unwrap();
+
+        assert!(verify(&pinned(vec![pin], true)).is_ok());
+        let err = verify(&pinned(vec![[0; 32]], true)).unwrap_err();
+        assert!(err.to_string().contains(&fingerprint_hex(leaf.as_ref())));
+        // A matching pin does not excuse an untrusted chain.
+        assert!(verify(&pinned(vec![pin], false)).is_err());
+    }
+
+    #[test]
+    fn builds_clients_from_network_settings() {
+        let settings = |network: NetworkSettings| ClientSettings {
+            connect_timeout: Duration::from_secs(1),
+            network: Some(network),
+        };
+        settings(NetworkSettings {
+            socks_proxy: Some("socks5h://127.0.0.1:1080".to_string()),
+            ca_files: vec![fixture("ca.pem")],
+            client_cert: Some(fixture("leaf.pem")),
+            client_key: Some(fixture("leaf.key")),
+            pin_sha256: vec!["00".repeat(32)],
+            ..Default::default()
+        })
+        .build()
+        .unwrap();
+
+        let err = settings(NetworkSettings {
+            ca_files: vec![fixture("leaf.key")],
+            ..Default::default()
+        })
+        .build()
+        .unwrap_err();
+        assert!(format!("{:#}", err).contains("No certificates found"));
+
+        let err = settings(NetworkSettings {
+            client_cert: Some(fixture("leaf.pem")),
+            client_key: Some(fixture("ca.pem")),
+            ..Default::default()
+        })
+        .build()
+        .unwrap_err();
+        assert!(format!("{:#}", err).contains("client key"));
+    }
+}
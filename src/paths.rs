@@ -0,0 +1,231 @@
+//! Mapping inbound Anthropic endpoints onto a provider's upstream paths.
+
+use anyhow::Result;
+use std::fmt::Write;
+
+use crate::config::{PathMap, Provider};
+use crate::headers::{self, TemplateContext};
+use crate::proxy::ProxyError;
+
+/// The inbound endpoints `paths` can remap.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Endpoint {
+    Messages,
+    CountTokens,
+    Models,
+    Batches,
+}
+
+impl Endpoint {
+    /// The endpoint `path` belongs to, and whatever follows its root, such as
+    /// a model or batch ID.
+    pub fn classify(path: &str) -> Option<(Self, &str)> {
+        let endpoints = [
+            ("/v1/messages/count_tokens", Endpoint::CountTokens),
+            ("/v1/messages/batches", Endpoint::Batches),
+            ("/v1/messages", Endpoint::Messages),
+            ("/v1/models", Endpoint::Models),
+        ];
+        endpoints.into_iter().find_map(|(root, endpoint)| {
+            let rest = path.strip_prefix(root)?;
+            (rest.is_empty() || rest.starts_with('/')).then_some((endpoint, rest))
+        })
+    }
+
+    fn template(self, paths: &PathMap) -> Option<&str> {
+        match self {
+            Endpoint::Messages => paths.messages.as_deref(),
+            Endpoint::CountTokens => paths.count_tokens.as_deref(),
+            Endpoint::Models => paths.models.as_deref(),
+            Endpoint::Batches => paths.batches.as_deref(),
+        }
+    }
+}
+
+/// `value` percent-encoded as a single path segment, so a model name from the
+/// client cannot add segments, climb out of the template or start a query.
+fn path_segment(value: &str) -> Result<String> {
+    // Parsers treat encoded dots as dot segments too, so these cannot be
+    // made safe by escaping.
+    if value == "." || value == ".." {
+        return Err(ProxyError::invalid_request(format!(
+            "Model '{}' cannot be used in an upstream path",
+            value
+        ))
+        .into());
+    }
+    let mut segment = String::new();
+    for byte in value.bytes() {
+        if byte.is_ascii_alphanumeric() || b"-._~:@".contains(&byte) {
+            segment.push(byte as char);
+        } else {
+            write!(segment, "%{:02X}", byte)?;
+        }
+    }
+    Ok(segment)
+}
+
+/// The path, and possibly a query, to append to `base_url`. `route_path` is
+/// `inbound_path` or, for translated requests, the Chat Completions path.
+/// Without a `paths` entry for the endpoint the old heuristic applies: `/v1`
+/// is kept when `base_url` is a bare host and stripped otherwise.
+pub fn upstream_path(
+    provider: &Provider,
+    inbound_path: &str,
+    route_path: &str,
+    ctx: &TemplateContext,
+) -> Result<String> {
+    let mapped = Endpoint::classify(inbound_path).and_then(|(endpoint, rest)| {
+        let template = endpoint.template(provider.paths.as_ref()?)?;
+        Some((template, rest))
+    });
+    if let Some((template, rest)) = mapped {
+        let model = ctx.model.map(path_segment).transpose()?;
+        let upstream_model = ctx.upstream_model.map(path_segment).transpose()?;
+        let ctx = TemplateContext {
+            model: model.as_deref(),
+            upstream_model: upstream_model.as_deref(),
+            ..*ctx
+        };
+        let rendered = headers::render(template, &ctx)?;
+        // Anything past the endpoint root goes before the template's query.
+        return Ok(match rendered.split_once('?') {
+            Some((path, query)) => format!("{}{}?{}", path, rest, query),
+            None => format!("{}{}", rendered, rest),
+        });
+    }
+
+    let base_has_path = url::Url::parse(provider.base_url.trim_end_matches('/'))
+        .map(|u| u.path() != "/" && !u.path().is_empty())
+        .unwrap_or(false);
+    Ok(if base_has_path {
+        route_path.strip_prefix("/v1").unwrap_or(route_path)
+    } else {
+        route_path
+    }
+    .to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn provider(base_url: &str, paths: Option<PathMap>) -> Provider {
+        Provider {
+            base_url: base_url.to_string(),
+            paths,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn classifies_endpoints() {
+        assert_eq!(
+            Endpoint::classify("/v1/messages"),
+            Some((Endpoint::Messages, ""))
+        );
+        assert_eq!(
+            Endpoint::classify("/v1/messages/batches/msgbatch_1/results"),
+            Some((Endpoint::Batches, "/msgbatch_1/results"))
+        );
+        assert_eq!(
+            Endpoint::classify("/v1/models/claude-opus"),
+            Some((Endpoint::Models, "/claude-opus"))
+        );
+        assert_eq!(Endpoint::classify("/v1/messagesx"), None);
+        assert_eq!(Endpoint::classify("/v1/complete"), None);
+    }
+
+    #[test]
+    fn heuristic_applies_without_paths() {
+        let ctx = TemplateContext::default();
+        let bare = provider("https://api.anthropic.com", None);
+        let with_path = provider("https://open.z.ai/api/paas/v4", None);
+        assert_eq!(
+            upstream_path(&bare, "/v1/messages", "/v1/messages", &ctx).unwrap(),
+            "/v1/messages"
+        );
+        assert_eq!(
+            upstream_path(&with_path, "/v1/messages", "/v1/chat/completions", &ctx).unwrap(),
+            "/chat/completions"
+        );
+    }
+
+    #[test]
+    fn templates_override_the_heuristic() {
+        let gateway = provider(
+            "https://gw.corp/anthropic",
+            Some(PathMap {
+                messages: Some("/v1/messages".to_string()),
+                models: Some("/v1/models".to_string()),
+                ..Default::default()
+            }),
+        );
+        let ctx = TemplateContext::default();
+        assert_eq!(
+            upstream_path(&gateway, "/v1/messages", "/v1/messages", &ctx).unwrap(),
+            "/v1/messages"
+        );
+        assert_eq!(
+            upstream_path(&gateway, "/v1/models/x", "/v1/models/x", &ctx).unwrap(),
+            "/v1/models/x"
+        );
+        // Unmapped endpoints still use the heuristic.
+        assert_eq!(
+            upstream_path(
+                &gateway,
+                "/v1/messages/count_tokens",
+                "/v1/messages/count_tokens",
+                &ctx
+            )
+            .unwrap(),
+            "/messages/count_tokens"
+        );
+
+        let azure = provider(
+            "https://res.openai.azure.com",
+            Some(PathMap {
+                messages: Some(
+                    "/openai/deployments/{upstream_model}/chat/completions?api-version=2024-10-21"
+                        .to_string(),
+                ),
+                ..Default::default()
+            }),
+        );
+        let ctx = TemplateContext {
+            upstream_model: Some("gpt-4o"),
+            ..Default::default()
+        };
+        assert_eq!(
+            upstream_path(&azure, "/v1/messages", "/v1/chat/completions", &ctx).unwrap(),
+            "/openai/deployments/gpt-4o/chat/completions?api-version=2024-10-21"
+        );
+    }
+
+    #[test]
+    fn models_are_encoded_as_one_segment() {
+        let gateway = provider(
+            "https://gw.corp",
+            Some(PathMap {
+                messages: Some("/deployments/{model}/messages?v=1".to_string()),
+                ..Default::default()
+            }),
+        );
+        let path = |model| {
+            let ctx = TemplateContext {
+                model: Some(model),
+                ..Default::default()
+            };
+            upstream_path(&gateway, "/v1/messages", "/v1/messages", &ctx)
+        };
+        assert_eq!(
+            path("a/../b?admin=1#x").unwrap(),
+            "/deployments/a%2F..%2Fb%3Fadmin%3D1%23x/messages?v=1"
+        );
+        assert_eq!(
+            path("glm-4.7").unwrap(),
+            "/deployments/glm-4.7/messages?v=1"
+        );
+        assert!(path("..").is_err());
+    }
+}
@@ -1,44 +1,350 @@
 use anyhow::{bail, Context, Result};
 use bytes::Bytes;
-use http_body_util::{BodyExt, Full};
+use futures_util::{StreamExt, TryStreamExt};
+use http_body_util::combinators::UnsyncBoxBody;
+use http_body_util::{BodyExt, Full, StreamBody};
+use hyper::body::Frame;
+use hyper::header::{HeaderName, HeaderValue};
 use hyper::server::conn::http1;
 use hyper::service::service_fn;
-use hyper::{body::Incoming, Request, Response};
+use hyper::{body::Incoming, HeaderMap, Method, Request, Response};
 use hyper_util::rt::TokioIo;
+use hyper_util::server::graceful::GracefulShutdown;
 use reqwest::Client;
+use std::collections::HashMap;
 use std::net::SocketAddr;
+use std::pin::Pin;
 use std::sync::Arc;
+use std::task::{self, Poll};
+use std::time::{Instant, SystemTime};
 use tokio::net::TcpListener;
 use tokio::sync::RwLock;
 
-use crate::config::ProfileConfig;
-use crate::rewrite::rewrite_model;
+use crate::admin::{self, ActiveGuard, ActiveRequests, AdminEndpoint};
+use crate::betas;
+use crate::budget::{BudgetCheck, BudgetLedger};
+use crate::capabilities;
+use crate::cassette::{self, CassetteMode, RecordedRequest};
+use crate::config::{ApiFormat, AuthScheme, CountTokensMode, ModelList, ProfileConfig, Provider};
+use crate::daemon;
+use crate::exchange::{self, Exchange};
+use crate::headers;
+use crate::health;
+use crate::limits::{self, LimitPermit, LimiterRegistry};
+use crate::metrics::{self, Metrics};
+use crate::models;
+use crate::openai;
+use crate::outbound::ClientSettings;
+use crate::paths;
+use crate::retry;
+use crate::rewrite::{classify_model, rewrite_model};
+use crate::timeouts::Deadlines;
+use crate::tokens;
+use crate::transcript::TranscriptSettings;
+use crate::usage;
+
+pub type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+/// Response body served to clients: either a buffered payload or a
+/// passthrough stream of upstream chunks.
+pub type ProxyBody = UnsyncBoxBody<Bytes, BoxError>;
+
+fn full_body(bytes: impl Into<Bytes>) -> ProxyBody {
+    Full::new(bytes.into())
+        .map_err(|never| match never {})
+        .boxed_unsync()
+}
+
+/// Forward upstream chunks as they arrive. Each chunk becomes its own frame so
+/// SSE events are flushed to the client immediately. If the client goes away,
+/// hyper drops this body, which drops the reqwest stream and closes the
+/// upstream connection.
+fn streaming_body(upstream_resp: reqwest::Response) -> ProxyBody {
+    let stream = upstream_resp
+        .bytes_stream()
+        .map_ok(Frame::data)
+        .map_err(|e| Box::new(e) as BoxError);
+    StreamBody::new(stream).boxed_unsync()
+}
+
+/// Stream a Chat Completions SSE response back as Anthropic SSE events,
+/// translating chunk by chunk so tokens still reach the client as they arrive.
+fn translated_streaming_body(upstream_resp: reqwest::Response) -> ProxyBody {
+    let upstream = upstream_resp.bytes_stream().boxed();
+    let stream = futures_util::stream::unfold(
+        (upstream, Some(openai::StreamTranslator::new())),
+        |(mut upstream, translator)| async move {
+            let mut translator = translator?;
+            loop {
+                match upstream.next().await {
+                    Some(Ok(chunk)) => {
+                        let out = translator.push(&chunk);
+                        if !out.is_empty() {
+                            let frame = Frame::data(Bytes::from(out));
+                            return Some((Ok(frame), (upstream, Some(translator))));
+                        }
+                    }
+                    Some(Err(e)) => return Some((Err(Box::new(e) as BoxError), (upstream, None))),
+                    None => {
+                        let frame = Frame::data(Bytes::from(translator.finish()));
+                        return Some((Ok(frame), (upstream, None)));
+                    }
+                }
+            }
+        },
+    );
+    StreamBody::new(stream).boxed_unsync()
+}
+
+/// Buffer a unary Chat Completions response and return it as an Anthropic
+/// `message` (or Anthropic error envelope for non-2xx statuses).
+async fn translated_unary_body(upstream_resp: reqwest::Response) -> Result<ProxyBody> {
+    let status = upstream_resp.status();
+    let bytes = upstream_resp.bytes().await?;
+    let json = match serde_json::from_slice::<serde_json::Value>(&bytes) {
+        Ok(json) => json,
+        Err(_) if status.is_success() => return Ok(full_body(bytes)),
+        Err(_) => serde_json::json!({"error": {"message": String::from_utf8_lossy(&bytes)}}),
+    };
+    let translated = if status.is_success() {
+        openai::openai_to_anthropic_response(&json)
+    } else {
+        openai::openai_to_anthropic_error(status.as_u16(), &json)
+    };
+    Ok(full_body(serde_json::to_vec(&translated)?))
+}
+
+/// Wraps a body so `guard` is released only once the body is finished or the
+/// client disconnects, e.g. to hold a concurrency slot for a whole stream.
+struct GuardedBody<G> {
+    inner: ProxyBody,
+    _guard: G,
+}
+
+impl<G: Send + Unpin + 'static> hyper::body::Body for GuardedBody<G> {
+    type Data = Bytes;
+    type Error = BoxError;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut task::Context<'_>,
+    ) -> Poll<Option<Result<Frame<Bytes>, BoxError>>> {
+        Pin::new(&mut self.inner).poll_frame(cx)
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> hyper::body::SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+fn guard_body<G: Send + Unpin + 'static>(inner: ProxyBody, guard: G) -> ProxyBody {
+    GuardedBody {
+        inner,
+        _guard: guard,
+    }
+    .boxed_unsync()
+}
+
+/// Armed while a request waits for its response. hyper drops the request's
+/// future when the client disconnects, which drops the upstream request with
+/// it; this only makes that visible in the log.
+struct Disconnected {
+    method: Method,
+    path: String,
+    started: Instant,
+    armed: bool,
+}
+
+impl Drop for Disconnected {
+    fn drop(&mut self) {
+        if self.armed {
+            eprintln!(
+                "[proxy] client disconnected after {:.1}s; aborted {} {} upstream",
+                self.started.elapsed().as_secs_f64(),
+                self.method,
+                self.path
+            );
+        }
+    }
+}
+
+/// An error the proxy answers itself, with a specific status and Anthropic
+/// error type instead of the generic 502 `proxy_error`.
+#[derive(Debug)]
+pub struct ProxyError {
+    pub status: u16,
+    pub error_type: &'static str,
+    pub message: String,
+}
+
+impl ProxyError {
+    pub fn rate_limited(message: impl Into<String>) -> Self {
+        Self {
+            status: 429,
+            error_type: "rate_limit_error",
+            message: message.into(),
+        }
+    }
+
+    pub fn invalid_request(message: impl Into<String>) -> Self {
+        Self {
+            status: 400,
+            error_type: "invalid_request_error",
+            message: message.into(),
+        }
+    }
+
+    pub fn budget_exceeded(message: impl Into<String>) -> Self {
+        Self {
+            status: 402,
+            error_type: "billing_error",
+            message: message.into(),
+        }
+    }
+}
+
+impl std::fmt::Display for ProxyError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl std::error::Error for ProxyError {}
+
+/// A config that failed validation on reload and was not applied.
+#[derive(Debug)]
+pub struct InvalidConfig(pub Vec<String>);
+
+impl std::fmt::Display for InvalidConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Config has errors and was not applied: {}",
+            self.0.join("; ")
+        )
+    }
+}
+
+impl std::error::Error for InvalidConfig {}
+
+/// Response header naming the provider that answered.
+const PROVIDER_HEADER: &str = "x-claude-model-switch-provider";
+
+fn is_event_stream(headers: &reqwest::header::HeaderMap) -> bool {
+    headers
+        .get(reqwest::header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .map(|v| v.starts_with("text/event-stream"))
+        .unwrap_or(false)
+}
 
 pub struct ProxyState {
     pub config: RwLock<ProfileConfig>,
-    pub client: Client,
+    /// One HTTP client per provider, kept across reloads and rebuilt only
+    /// when that provider's connection settings change.
+    clients: std::sync::Mutex<HashMap<String, (ClientSettings, Client)>>,
+    pub limiters: LimiterRegistry,
+    pub budgets: Arc<BudgetLedger>,
+    pub metrics: Arc<Metrics>,
+    pub active: Arc<ActiveRequests>,
+    /// Bearer token for the `/_cms/` admin API.
+    pub admin_token: String,
+    /// Why the last reload was rejected, until a later one succeeds.
+    pub last_reload_error: std::sync::Mutex<Option<String>>,
+    /// Record or replay upstream exchanges (`start --record/--replay`).
+    pub cassettes: Option<CassetteMode>,
 }
 
 impl ProxyState {
     pub fn new(config: ProfileConfig) -> Self {
         Self {
+            budgets: Arc::new(BudgetLedger::load(&config)),
+            clients: std::sync::Mutex::default(),
+            limiters: LimiterRegistry::default(),
+            metrics: Arc::new(Metrics::default()),
+            active: Arc::new(ActiveRequests::default()),
+            admin_token: config
+                .admin_token
+                .clone()
+                .unwrap_or_else(admin::generate_token),
             config: RwLock::new(config),
-            client: Client::new(),
+            last_reload_error: std::sync::Mutex::new(None),
+            cassettes: None,
+        }
+    }
+
+    /// The client for `name`, built from its timeouts and network settings.
+    pub fn client_for(&self, name: &str, provider: &Provider) -> Result<Client> {
+        let settings = ClientSettings::of(provider);
+        let mut clients = self.clients.lock().unwrap();
+        if let Some((cached, client)) = clients.get(name) {
+            if *cached == settings {
+                return Ok(client.clone());
+            }
         }
+        let client = settings
+            .build()
+            .with_context(|| format!("Failed to set up the HTTP client for {}", name))?;
+        clients.insert(name.to_string(), (settings, client.clone()));
+        Ok(client)
     }
 
+    /// Re-read the config file, keeping the current config if the new one
+    /// does not validate.
     pub async fn reload_config(&self) -> Result<()> {
-        let new_config = ProfileConfig::load()?;
+        let result = ProfileConfig::load().and_then(|new_config| {
+            let errors = new_config.validate();
+            if !errors.is_empty() {
+                return Err(InvalidConfig(errors).into());
+            }
+            Ok(new_config)
+        });
+        match result {
+            Ok(new_config) => {
+                self.apply_config(new_config).await;
+                Ok(())
+            }
+            Err(e) => {
+                self.reload_failed(format!("{:#}", e));
+                Err(e)
+            }
+        }
+    }
+
+    pub async fn apply_config(&self, new_config: ProfileConfig) {
+        // Rebuilding re-reads the whole usage store, so it only happens when
+        // budgets or prices change, and never while requests wait on the lock.
+        if self.budgets.is_stale(&new_config) {
+            let budgets = self.budgets.clone();
+            let rebuilt = new_config.clone();
+            if let Err(e) = tokio::task::spawn_blocking(move || budgets.rebuild(&rebuilt)).await {
+                eprintln!("[budget] failed to rebuild budgets: {}", e);
+            }
+        }
         let mut config = self.config.write().await;
+        self.clients
+            .lock()
+            .unwrap()
+            .retain(|name, _| new_config.providers.contains_key(name));
+        self.limiters.prune(&new_config);
         *config = new_config;
-        Ok(())
+        *self.last_reload_error.lock().unwrap() = None;
+    }
+
+    /// Remember a rejected reload so `/readyz` reports it.
+    pub fn reload_failed(&self, error: String) {
+        *self.last_reload_error.lock().unwrap() = Some(error);
     }
 }
 
 #[derive(Debug, Clone)]
 struct RouteResolution {
     provider_name: String,
-    provider: crate::config::Provider,
+    provider: Provider,
     upstream_path: String,
 }
 
@@ -70,127 +376,775 @@ fn resolve_route(path: &str, config: &ProfileConfig) -> Result<RouteResolution>
 async fn handle_request(
     req: Request<Incoming>,
     state: Arc<ProxyState>,
-) -> Result<Response<Full<Bytes>>, hyper::Error> {
-    match proxy_request(req, state).await {
-        Ok(resp) => Ok(resp),
+) -> Result<Response<ProxyBody>, hyper::Error> {
+    // Served by the proxy itself, never forwarded upstream.
+    if req.method() == Method::GET && req.uri().path() == metrics::METRICS_PATH {
+        return Ok(Response::builder()
+            .header("content-type", metrics::CONTENT_TYPE)
+            .body(full_body(state.metrics.render()))
+            .unwrap());
+    }
+    if req.method() == Method::GET && req.uri().path() == health::HEALTH_PATH {
+        return Ok(health::health_response());
+    }
+    if req.method() == Method::GET && req.uri().path() == health::READY_PATH {
+        return Ok(health::ready_response(state).await);
+    }
+    if req.uri().path().starts_with(admin::ADMIN_PREFIX) {
+        return Ok(admin::handle(req, state).await);
+    }
+
+    let in_flight = state.metrics.in_flight();
+    let active = state.active.begin(req.method(), req.uri().path());
+    let mut disconnected = Disconnected {
+        method: req.method().clone(),
+        path: req.uri().path().to_string(),
+        started: Instant::now(),
+        armed: true,
+    };
+    let result = proxy_request(req, state.clone(), &active).await;
+    disconnected.armed = false;
+    match result {
+        Ok(resp) => Ok(resp.map(|body| guard_body(body, (in_flight, active)))),
         Err(e) => {
-            let body = serde_json::json!({
-                "error": {
-                    "type": "proxy_error",
-                    "message": format!("{:#}", e)
+            let (status, error_type) = match e.downcast_ref::<ProxyError>() {
+                Some(err) => (err.status, err.error_type),
+                None => (502, "proxy_error"),
+            };
+            state.metrics.record_rejected(status);
+            Ok(error_response(status, error_type, &format!("{:#}", e)))
+        }
+    }
+}
+
+pub fn json_response(status: u16, body: &serde_json::Value) -> Response<ProxyBody> {
+    Response::builder()
+        .status(status)
+        .header("content-type", "application/json")
+        .body(full_body(serde_json::to_vec(body).unwrap()))
+        .unwrap()
+}
+
+/// An Anthropic-shaped error envelope.
+pub fn error_response(status: u16, error_type: &str, message: &str) -> Response<ProxyBody> {
+    json_response(
+        status,
+        &serde_json::json!({
+            "type": "error",
+            "error": {
+                "type": error_type,
+                "message": message
+            }
+        }),
+    )
+}
+
+/// Inbound request parts, kept so the same request can be replayed against
+/// each provider in a fallback chain.
+struct InboundRequest {
+    method: Method,
+    headers: HeaderMap,
+    path: String,
+    query: Option<String>,
+    body: Bytes,
+}
+
+/// Providers to try for a route, in order: the resolved provider followed by
+/// its `fallback` list. Unknown or repeated names are skipped.
+fn provider_chain(route: &RouteResolution, config: &ProfileConfig) -> Vec<(String, Provider)> {
+    let mut chain = vec![(route.provider_name.clone(), route.provider.clone())];
+    for name in &route.provider.fallback {
+        if chain.iter().any(|(existing, _)| existing == name) {
+            continue;
+        }
+        match config.providers.get(name) {
+            Some(provider) => chain.push((name.clone(), provider.clone())),
+            None => eprintln!(
+                "[proxy] fallback provider '{}' for '{}' not found, skipping",
+                name, route.provider_name
+            ),
+        }
+    }
+    chain
+}
+
+/// Swap out or drop providers whose budget is exhausted. An exhausted
+/// primary without a reroute target rejects the request; exhausted
+/// fallbacks are skipped.
+fn apply_budgets(
+    chain: Vec<(String, Provider)>,
+    config: &ProfileConfig,
+    budgets: &BudgetLedger,
+) -> Result<Vec<(String, Provider)>> {
+    let mut allowed: Vec<(String, Provider)> = Vec::new();
+    for (i, (name, provider)) in chain.into_iter().enumerate() {
+        let entry = match budgets.check(&name) {
+            BudgetCheck::Allow => (name, provider),
+            BudgetCheck::Exceeded {
+                message,
+                reroute_to,
+            } => {
+                let target = reroute_to
+                    .filter(|target| budgets.check(target) == BudgetCheck::Allow)
+                    .and_then(|target| Some((target.clone(), config.providers.get(&target)?)));
+                match target {
+                    Some((target, target_provider)) => {
+                        eprintln!("[budget] {}; rerouting {} to {}", message, name, target);
+                        (target, target_provider.clone())
+                    }
+                    None if i == 0 => return Err(ProxyError::budget_exceeded(message).into()),
+                    None => {
+                        eprintln!("[budget] {}; skipping fallback {}", message, name);
+                        continue;
+                    }
                 }
-            });
-            Ok(Response::builder()
-                .status(502)
-                .header("content-type", "application/json")
-                .body(Full::new(Bytes::from(serde_json::to_vec(&body).unwrap())))
-                .unwrap())
+            }
+        };
+        if !allowed.iter().any(|(existing, _)| *existing == entry.0) {
+            allowed.push(entry);
         }
     }
+    Ok(allowed)
+}
+
+/// Upstream failures that warrant trying the next provider in the chain:
+/// any 5xx, which includes Anthropic's 529 overloaded.
+fn should_fall_back(status: reqwest::StatusCode) -> bool {
+    status.is_server_error()
 }
 
 async fn proxy_request(
     req: Request<Incoming>,
     state: Arc<ProxyState>,
-) -> Result<Response<Full<Bytes>>> {
+    active: &ActiveGuard,
+) -> Result<Response<ProxyBody>> {
     let path = req.uri().path().to_string();
     let query = req.uri().query().map(ToString::to_string);
     let method = req.method().clone();
     let headers = req.headers().clone();
-    let route = {
+    let (route, chain, transcript) = {
         let config = state.config.read().await;
-        resolve_route(&path, &config)?
+        let route = resolve_route(&path, &config)?;
+        // A budget rejection is answered once the body is read, so it is
+        // recorded like any other response.
+        let chain = apply_budgets(provider_chain(&route, &config), &config, &state.budgets);
+        let transcript = match &config.transcript {
+            Some(t) => Some(TranscriptSettings::resolve(t, config.secrets())?),
+            None => None,
+        };
+        (route, chain, transcript)
     };
 
     // Read request body
-    let body_bytes = req
+    let body = req
         .collect()
         .await
         .map_err(|e| anyhow::anyhow!("Failed to read request body: {}", e))?
         .to_bytes();
 
+    let inbound = InboundRequest {
+        method,
+        headers,
+        path: route.upstream_path,
+        query,
+        body,
+    };
+    let started = Instant::now();
+    let timestamp = SystemTime::now();
+    let model = serde_json::from_slice::<serde_json::Value>(&inbound.body)
+        .ok()
+        .and_then(|json| json.get("model")?.as_str().map(ToString::to_string));
+    let answered = match chain {
+        Ok(chain) => answer(&state, &inbound, &chain, active, model.as_deref()).await,
+        Err(e) => Err(e),
+    };
+    let answer = match answered {
+        Ok(answer) => answer,
+        Err(e) => match e.downcast_ref::<ProxyError>() {
+            Some(err) => Answer::local(error_response(
+                err.status,
+                err.error_type,
+                &format!("{:#}", e),
+            )),
+            None => return Err(e),
+        },
+    };
+
+    // Only provider responses carry usage worth recording and charging.
+    let relayed = answer.provider.is_some();
+    let exchange = Exchange {
+        timestamp,
+        started,
+        method: inbound.method.to_string(),
+        route: path,
+        provider: answer
+            .provider
+            .unwrap_or_else(|| exchange::LOCAL_PROVIDER.to_string()),
+        model,
+        upstream_model: answer.upstream_model,
+        status: answer.response.status().as_u16(),
+        stream: is_event_stream(answer.response.headers()),
+        request_headers: inbound.headers,
+        request_body: inbound.body,
+        transcript,
+        usage_store: usage::default_path().ok().filter(|_| relayed),
+        budgets: relayed.then(|| state.budgets.clone()),
+        metrics: Some(state.metrics.clone()),
+    };
+    let permit = answer.permit;
+    Ok(answer
+        .response
+        .map(|body| guard_body(exchange::observe(body, exchange), permit)))
+}
+
+/// A response to an inbound request, and who produced it.
+struct Answer {
+    response: Response<ProxyBody>,
+    /// The provider that answered, or `None` when the proxy answered itself.
+    provider: Option<String>,
+    /// The `model` sent upstream after tier rewriting.
+    upstream_model: Option<String>,
+    /// The rate limit slot held until the response body is relayed.
+    permit: Option<LimitPermit>,
+}
+
+impl Answer {
+    fn local(response: Response<ProxyBody>) -> Self {
+        Self {
+            response,
+            provider: None,
+            upstream_model: None,
+            permit: None,
+        }
+    }
+}
+
+/// Answer the request from the first provider in the chain that succeeds,
+/// or locally where the proxy can.
+async fn answer(
+    state: &ProxyState,
+    inbound: &InboundRequest,
+    chain: &[(String, Provider)],
+    active: &ActiveGuard,
+    model: Option<&str>,
+) -> Result<Answer> {
+    if inbound.method == Method::GET {
+        if let Some((paths::Endpoint::Models, rest)) = paths::Endpoint::classify(&inbound.path) {
+            let (provider_name, provider) = &chain[0];
+            if let Some(response) =
+                serve_models(state, inbound, provider_name, provider, rest).await?
+            {
+                return Ok(Answer::local(response));
+            }
+        }
+    }
+    let counting = inbound.method == Method::POST
+        && paths::Endpoint::classify(&inbound.path) == Some((paths::Endpoint::CountTokens, ""));
+
+    // Nothing has been sent downstream until a response is relayed, so each
+    // failed attempt can still fall through to the next provider.
+    let last = chain.len() - 1;
+    for (i, (provider_name, provider)) in chain.iter().enumerate() {
+        let next = chain.get(i + 1).map(|(name, _)| name.as_str());
+        active.set_upstream(provider_name, model);
+        let count_mode = provider.count_tokens_mode();
+        if counting && count_mode == CountTokensMode::Local {
+            return count_tokens_locally(provider_name, provider, inbound).map(Answer::local);
+        }
+        match send_with_retries(state, inbound, provider_name, provider).await {
+            Ok(upstream)
+                if counting
+                    && count_mode == CountTokensMode::Fallback
+                    && lacks_endpoint(upstream.response.status()) =>
+            {
+                eprintln!(
+                    "[proxy] {}: count_tokens returned {}, counting locally",
+                    provider_name,
+                    upstream.response.status()
+                );
+                return count_tokens_locally(provider_name, provider, inbound).map(Answer::local);
+            }
+            Ok(upstream) if i < last && should_fall_back(upstream.response.status()) => {
+                eprintln!(
+                    "[proxy] {} returned {}, falling back to {}",
+                    provider_name,
+                    upstream.response.status(),
+                    next.unwrap_or_default()
+                );
+            }
+            Ok(mut upstream) => {
+                let permit = upstream.permit.take();
+                let upstream_model = upstream.upstream_model.clone();
+                return Ok(Answer {
+                    response: relay_response(provider_name, upstream).await?,
+                    provider: Some(provider_name.clone()),
+                    upstream_model,
+                    permit,
+                });
+            }
+            Err(e) if i < last => {
+                eprintln!(
+                    "[proxy] {} failed: {:#}; falling back to {}",
+                    provider_name,
+                    e,
+                    next.unwrap_or_default()
+                );
+            }
+            Err(e) => return Err(e),
+        }
+    }
+    unreachable!("provider chain always contains the routed provider")
+}
+
+/// Statuses meaning the provider has no such endpoint.
+fn lacks_endpoint(status: reqwest::StatusCode) -> bool {
+    matches!(status.as_u16(), 404 | 405 | 501)
+}
+
+/// Answer `count_tokens` with the bundled tokenizer approximation.
+fn count_tokens_locally(
+    provider_name: &str,
+    provider: &Provider,
+    inbound: &InboundRequest,
+) -> Result<Response<ProxyBody>> {
+    let body: serde_json::Value = serde_json::from_slice(&inbound.body).map_err(|e| {
+        ProxyError::invalid_request(format!("Request body is not valid JSON: {}", e))
+    })?;
+    let input_tokens = tokens::count_input_tokens(&body, &provider.token_counting());
+    eprintln!(
+        "[proxy] {}: counted {} input tokens locally",
+        provider_name, input_tokens
+    );
+    Ok(json_response(
+        200,
+        &serde_json::json!({ "input_tokens": input_tokens }),
+    ))
+}
+
+/// Answer `GET /v1/models` from the tier mapping, as the provider's
+/// `model_list` says. `None` means the request goes upstream as usual.
+async fn serve_models(
+    state: &ProxyState,
+    inbound: &InboundRequest,
+    provider_name: &str,
+    provider: &Provider,
+    rest: &str,
+) -> Result<Option<Response<ProxyBody>>> {
+    let mode = provider.model_list();
+    if mode == ModelList::Upstream {
+        return Ok(None);
+    }
+    if let Some(id) = rest.strip_prefix('/') {
+        let response = match models::find(provider, id) {
+            Some(model) => json_response(200, &model),
+            None if mode == ModelList::Merge => return Ok(None),
+            None => error_response(404, "not_found_error", &format!("model: {}", id)),
+        };
+        return Ok(Some(models_response(provider_name, response)));
+    }
+
+    let tiers = models::tier_models(provider);
+    let listed = if mode == ModelList::Merge {
+        let upstream = match send_with_retries(state, inbound, provider_name, provider).await {
+            Ok(upstream) if upstream.response.status().is_success() => upstream
+                .response
+                .json::<serde_json::Value>()
+                .await
+                .map(|body| models::upstream_models(&body))
+                .map_err(anyhow::Error::from),
+            Ok(upstream) => Err(anyhow::anyhow!("returned {}", upstream.response.status())),
+            Err(e) => Err(e),
+        };
+        match upstream {
+            Ok(upstream) => models::merge(tiers, upstream),
+            Err(e) => {
+                eprintln!(
+                    "[proxy] {}: upstream model list unavailable: {:#}; listing tier models only",
+                    provider_name, e
+                );
+                tiers
+            }
+        }
+    } else {
+        tiers
+    };
+    Ok(Some(models_response(
+        provider_name,
+        json_response(200, &models::page(listed)),
+    )))
+}
+
+/// Name the provider a synthesized model list is for, as relayed responses do.
+fn models_response(provider_name: &str, mut response: Response<ProxyBody>) -> Response<ProxyBody> {
+    if let Ok(value) = HeaderValue::from_str(provider_name) {
+        response.headers_mut().insert(PROVIDER_HEADER, value);
+    }
+    response
+}
+
+/// A response from one provider, plus what relaying it needs.
+struct UpstreamResponse {
+    response: reqwest::Response,
+    /// Whether the body is Chat Completions and must be translated back.
+    translate_openai: bool,
+    /// The `model` sent upstream after tier rewriting.
+    upstream_model: Option<String>,
+    /// Whether a streaming request was sent without streaming, so the
+    /// response must be replayed as events.
+    emulate_stream: bool,
+    /// The rate limit slot this attempt holds until its body is relayed.
+    permit: Option<LimitPermit>,
+}
+
+/// Send to one provider, retrying per its `retry` policy. Every retry happens
+/// before anything is relayed downstream, so the client only ever sees the
+/// final attempt's response.
+async fn send_with_retries(
+    state: &ProxyState,
+    inbound: &InboundRequest,
+    provider_name: &str,
+    provider: &Provider,
+) -> Result<UpstreamResponse> {
+    let Some(policy) = provider.retry.as_ref().filter(|p| p.max_attempts > 1) else {
+        return send_attempt(state, inbound, provider_name, provider).await;
+    };
+
+    let mut attempt = 1;
+    loop {
+        eprintln!(
+            "[proxy] {} attempt {}/{}",
+            provider_name, attempt, policy.max_attempts
+        );
+        let result = send_attempt(state, inbound, provider_name, provider).await;
+        if attempt >= policy.max_attempts {
+            return result;
+        }
+        let delay = match &result {
+            Ok(upstream) if retry::is_retryable(policy, upstream.response.status().as_u16()) => {
+                match retry::next_delay(policy, attempt, upstream.response.headers()) {
+                    Some(delay) => delay,
+                    None => {
+                        eprintln!(
+                            "[proxy] {} asked to wait longer than max_delay_ms, not retrying",
+                            provider_name
+                        );
+                        return result;
+                    }
+                }
+            }
+            Ok(_) => return result,
+            Err(e) if is_rejected_locally(e) => return result,
+            Err(_) => retry::backoff_delay(policy, attempt),
+        };
+        match &result {
+            Ok(upstream) => eprintln!(
+                "[proxy] {} attempt {}/{} returned {}; retrying in {}ms",
+                provider_name,
+                attempt,
+                policy.max_attempts,
+                upstream.response.status(),
+                delay.as_millis()
+            ),
+            Err(e) => eprintln!(
+                "[proxy] {} attempt {}/{} failed: {:#}; retrying in {}ms",
+                provider_name,
+                attempt,
+                policy.max_attempts,
+                e,
+                delay.as_millis()
+            ),
+        }
+        drop(result);
+        tokio::time::sleep(delay).await;
+        attempt += 1;
+    }
+}
+
+/// Whether the proxy refused the request itself, such as a request the
+/// provider's capabilities cannot serve. Retrying would not help.
+fn is_rejected_locally(e: &anyhow::Error) -> bool {
+    matches!(e.downcast_ref::<ProxyError>(), Some(err) if err.status < 500)
+}
+
+/// One upstream attempt under the provider's rate limits and timeouts,
+/// counted in the error metrics when it fails. Each attempt waits for its own
+/// rate limit slot, so retries count against `rpm` and `tpm` too.
+async fn send_attempt(
+    state: &ProxyState,
+    inbound: &InboundRequest,
+    provider_name: &str,
+    provider: &Provider,
+) -> Result<UpstreamResponse> {
+    let permit = match &provider.limits {
+        Some(limits) => Some(
+            state
+                .limiters
+                .get(provider_name, limits)
+                .acquire(provider_name, limits::estimate_tokens(&inbound.body))
+                .await?,
+        ),
+        None => None,
+    };
+    let deadlines = Deadlines::start(&provider.timeouts.clone().unwrap_or_default());
+    let send = send_upstream(state, inbound, provider_name, provider);
+    match deadlines.first_byte(provider_name, send).await {
+        Ok(mut upstream) => {
+            let status = upstream.response.status();
+            if !status.is_success() {
+                state
+                    .metrics
+                    .record_upstream_error(provider_name, status.as_str());
+            }
+            upstream.response =
+                deadlines.watch_body(provider_name, upstream.response, state.metrics.clone())?;
+            upstream.permit = permit;
+            Ok(upstream)
+        }
+        Err(e) if is_rejected_locally(&e) => Err(e),
+        Err(e) => {
+            let reason = match e.downcast_ref::<ProxyError>() {
+                Some(err) if err.status == 504 => "timeout",
+                _ => "network",
+            };
+            state.metrics.record_upstream_error(provider_name, reason);
+            Err(e)
+        }
+    }
+}
+
+/// Send the inbound request to one provider, applying its model mapping,
+/// format translation and auth.
+async fn send_upstream(
+    state: &ProxyState,
+    inbound: &InboundRequest,
+    provider_name: &str,
+    provider: &Provider,
+) -> Result<UpstreamResponse> {
+    // OpenAI-format providers get Messages requests translated to Chat Completions.
+    let translate_openai =
+        provider.api_format == ApiFormat::Openai && openai::is_messages_path(&inbound.path);
+
+    let beta_policy = provider
+        .header_policy
+        .as_ref()
+        .map(|policy| (policy, policy.decide(&inbound.headers)));
+    let mut dropped_fields = Vec::new();
+
     // Rewrite model in JSON body
-    let body_bytes = if !body_bytes.is_empty() {
-        if let Ok(mut json) = serde_json::from_slice::<serde_json::Value>(&body_bytes) {
-            if let Some(model) = json.get("model").and_then(|m| m.as_str()) {
-                let rewritten = rewrite_model(model, &route.provider);
+    let mut model = None;
+    let mut upstream_model = None;
+    let mut emulate_stream = false;
+    let body_bytes = if !inbound.body.is_empty() {
+        if let Ok(mut json) = serde_json::from_slice::<serde_json::Value>(&inbound.body) {
+            if let Some((_, decision)) = &beta_policy {
+                dropped_fields = betas::drop_dependent_fields(&mut json, &decision.stripped);
+            }
+            if let Some(requested) = json.get("model").and_then(|m| m.as_str()) {
+                let rewritten = rewrite_model(requested, provider);
+                model = Some(requested.to_string());
+                upstream_model = Some(rewritten.clone());
                 json["model"] = serde_json::Value::String(rewritten);
             }
+            // count_tokens is how Claude Code finds out a prompt is too long,
+            // so it is never rejected for being too long.
+            let sanitized =
+                paths::Endpoint::classify(&inbound.path) == Some((paths::Endpoint::Messages, ""));
+            if let Some(caps) = provider.capabilities.as_ref().filter(|_| sanitized) {
+                emulate_stream =
+                    !caps.streaming && json.get("stream").and_then(|s| s.as_bool()) == Some(true);
+                let changes = capabilities::sanitize(
+                    caps,
+                    &provider.token_counting(),
+                    provider_name,
+                    upstream_model.as_deref(),
+                    &mut json,
+                )?;
+                if !changes.is_empty() {
+                    eprintln!(
+                        "[proxy] {}: adapted request: {}",
+                        provider_name,
+                        changes.join(", ")
+                    );
+                }
+            }
+            if translate_openai {
+                json = openai::anthropic_to_openai_request(&json);
+            }
             Bytes::from(serde_json::to_vec(&json)?)
         } else {
-            body_bytes
+            inbound.body.clone()
         }
     } else {
-        body_bytes
+        inbound.body.clone()
     };
-
-    // Build upstream URL
-    // The incoming path starts with /v1 (Anthropic convention).
-    // If base_url has no path (just a host like https://api.anthropic.com),
-    // keep /v1. Otherwise strip it to avoid doubling with the provider's path.
-    let base = route.provider.base_url.trim_end_matches('/');
-    let base_has_path = url::Url::parse(base)
-        .map(|u| u.path() != "/" && u.path() != "")
-        .unwrap_or(false);
-    let upstream_path = if base_has_path {
-        route
-            .upstream_path
-            .strip_prefix("/v1")
-            .unwrap_or(&route.upstream_path)
+    if let Some((_, decision)) = beta_policy.as_ref().filter(|(_, d)| !d.stripped.is_empty()) {
+        let dropped = if dropped_fields.is_empty() {
+            String::new()
+        } else {
+            format!("; dropped {}", dropped_fields.join(", "))
+        };
+        eprintln!(
+            "[proxy] {}: stripped anthropic-beta {}{}",
+            provider_name,
+            decision.stripped.join(","),
+            dropped
+        );
+    }
+    let route_path = if translate_openai {
+        openai::CHAT_COMPLETIONS_PATH
     } else {
-        &route.upstream_path
+        inbound.path.as_str()
+    };
+
+    let template = headers::TemplateContext {
+        provider: provider_name,
+        route: &inbound.path,
+        role: model.as_deref().and_then(classify_model),
+        model: model.as_deref(),
+        upstream_model: upstream_model.as_deref(),
     };
-    let mut upstream_url = format!("{}{}", base, upstream_path);
-    if let Some(query) = query {
-        upstream_url.push('?');
-        upstream_url.push_str(&query);
+
+    // Build upstream URL from the provider's `paths`, or the /v1 heuristic.
+    let upstream_path = paths::upstream_path(provider, &inbound.path, route_path, &template)
+        .with_context(|| format!("Failed to build the upstream path for {}", provider_name))?;
+    let mut upstream_url = format!(
+        "{}{}",
+        provider.base_url.trim_end_matches('/'),
+        upstream_path
+    );
+    if let Some(query) = &inbound.query {
+        upstream_url.push(if upstream_path.contains('?') {
+            '&'
+        } else {
+            '?'
+        });
+        upstream_url.push_str(query);
     }
 
     // Build upstream request
-    let mut upstream_req = state.client.request(method.clone(), &upstream_url);
+    let mut upstream_req = state
+        .client_for(provider_name, provider)?
+        .request(inbound.method.clone(), &upstream_url);
 
-    // Copy relevant headers (skip hop-by-hop; forward inbound auth unless provider has explicit auth configured).
-    let provider_has_explicit_auth =
-        route.provider.api_key.is_some() || route.provider.auth_token.is_some();
-    for (name, value) in headers.iter() {
+    // Copy relevant headers (skip hop-by-hop; forward inbound auth unless the provider supplies its own).
+    let auth = provider
+        .auth
+        .clone()
+        .unwrap_or_else(|| provider.default_auth());
+    let credential = provider.credential().filter(|_| auth != AuthScheme::None);
+    let replace_inbound_auth = credential.is_some() || auth == AuthScheme::None;
+    let mut headers = HeaderMap::new();
+    for (name, value) in inbound.headers.iter() {
         let name_str = name.as_str().to_lowercase();
         if matches!(
             name_str.as_str(),
-            "host" | "connection" | "transfer-encoding" | "keep-alive"
+            "host" | "connection" | "transfer-encoding" | "keep-alive" | "content-length"
         ) {
             continue;
         }
-        if provider_has_explicit_auth && (name_str == "authorization" || name_str == "x-api-key") {
+        if replace_inbound_auth && (name_str == "authorization" || name_str == "x-api-key") {
             continue;
         }
-        upstream_req = upstream_req.header(name.clone(), value.clone());
+        // Anthropic-specific headers mean nothing to a Chat Completions upstream.
+        if translate_openai && name_str.starts_with("anthropic-") {
+            continue;
+        }
+        headers.append(name.clone(), value.clone());
     }
 
     // Set provider auth
-    if let Some(ref key) = route.provider.api_key {
-        upstream_req = upstream_req.header("x-api-key", key);
-        upstream_req = upstream_req.header("Authorization", format!("Bearer {}", key));
+    let mut auth_query = None;
+    if let Some(key) = credential {
+        match &auth {
+            AuthScheme::XApiKey => {
+                headers.insert("x-api-key", HeaderValue::from_str(key)?);
+            }
+            AuthScheme::Bearer => {
+                headers.insert(
+                    "authorization",
+                    HeaderValue::from_str(&format!("Bearer {}", key))?,
+                );
+            }
+            AuthScheme::Header(name) => {
+                headers.insert(
+                    HeaderName::try_from(name.as_str())?,
+                    HeaderValue::from_str(key)?,
+                );
+            }
+            AuthScheme::Query(param) => auth_query = Some((param.as_str(), key)),
+            AuthScheme::None => {}
+        }
+    }
+
+    if let Some((policy, decision)) = beta_policy.as_ref().filter(|_| !translate_openai) {
+        policy.apply_headers(&mut headers, decision);
     }
-    if let Some(ref token) = route.provider.auth_token {
-        upstream_req = upstream_req.header("Authorization", format!("Bearer {}", token));
+    headers::apply(&mut headers, provider, &template)
+        .with_context(|| format!("Failed to set headers for {}", provider_name))?;
+    upstream_req = upstream_req.headers(headers);
+    // The key stays out of `upstream_url`, which is logged and recorded.
+    if let Some(query) = auth_query {
+        upstream_req = upstream_req.query(&[query]);
     }
 
     if !body_bytes.is_empty() {
         upstream_req = upstream_req.header("content-type", "application/json");
     }
 
-    eprintln!("[proxy] -> {} {}", method, upstream_url);
+    // Cassettes see the request exactly as it would go upstream.
+    let cassette = state.cassettes.as_ref().map(|mode| {
+        (
+            mode,
+            RecordedRequest::normalized(&inbound.method, &upstream_url, &body_bytes),
+        )
+    });
+    if let Some((CassetteMode::Replay(dir), request)) = &cassette {
+        return Ok(UpstreamResponse {
+            response: cassette::replay(dir, request)?,
+            translate_openai,
+            upstream_model,
+            emulate_stream,
+            permit: None,
+        });
+    }
+
+    eprintln!("[proxy] -> {} {}", inbound.method, upstream_url);
 
     // Send
-    let upstream_resp = upstream_req
-        .body(body_bytes.to_vec())
+    let mut upstream_resp = upstream_req
+        .body(body_bytes)
         .send()
         .await
+        .map_err(|e| e.without_url())
         .with_context(|| format!("Failed to reach upstream: {}", upstream_url))?;
+    if let Some((CassetteMode::Record(dir), request)) = cassette {
+        upstream_resp = cassette::record(dir, request, upstream_resp)?;
+    }
+    Ok(UpstreamResponse {
+        response: upstream_resp,
+        translate_openai,
+        upstream_model,
+        emulate_stream,
+        permit: None,
+    })
+}
 
+/// Turn the chosen upstream response into the downstream response, streaming
+/// or translating the body as needed.
+async fn relay_response(
+    provider_name: &str,
+    upstream: UpstreamResponse,
+) -> Result<Response<ProxyBody>> {
+    let UpstreamResponse {
+        response: upstream_resp,
+        translate_openai,
+        emulate_stream,
+        ..
+    } = upstream;
     let status = upstream_resp.status();
     let resp_headers = upstream_resp.headers().clone();
-    let resp_body = upstream_resp.bytes().await?;
+    let streaming = is_event_stream(&resp_headers);
+    let emulate_stream = emulate_stream && status.is_success() && !streaming;
 
     let mut response = Response::builder().status(status.as_u16());
     for (name, value) in resp_headers.iter() {
@@ -198,20 +1152,77 @@ async fn proxy_request(
         if matches!(name_str.as_str(), "transfer-encoding" | "connection") {
             continue;
         }
+        // Streamed or translated bodies change length, so the header would lie.
+        if (streaming || translate_openai || emulate_stream) && name_str == "content-length" {
+            continue;
+        }
+        if (translate_openai || emulate_stream) && name_str == "content-type" {
+            continue;
+        }
         response = response.header(name.clone(), value.clone());
     }
-    response = response.header("x-claude-model-switch-provider", route.provider_name);
+    response = response.header(PROVIDER_HEADER, provider_name);
+
+    if emulate_stream {
+        let bytes = upstream_resp.bytes().await?;
+        let events = serde_json::from_slice::<serde_json::Value>(&bytes)
+            .ok()
+            .filter(|message| message.is_object())
+            .map(|message| {
+                if translate_openai {
+                    openai::openai_to_anthropic_response(&message)
+                } else {
+                    message
+                }
+            })
+            .and_then(|message| capabilities::message_events(&message));
+        let Some(events) = events else {
+            // Not a message to replay, so pass it on as the provider sent it.
+            eprintln!(
+                "[proxy] {}: response is not a message object, relaying it unchanged",
+                provider_name
+            );
+            response = response.header("content-type", "application/json");
+            return Ok(response.body(full_body(bytes)).unwrap());
+        };
+        response = response.header("content-type", "text/event-stream");
+        return Ok(response.body(full_body(events)).unwrap());
+    }
 
-    Ok(response.body(Full::new(resp_body)).unwrap())
+    let body = match (translate_openai, streaming) {
+        (true, true) => {
+            response = response.header("content-type", "text/event-stream");
+            translated_streaming_body(upstream_resp)
+        }
+        (true, false) => {
+            response = response.header("content-type", "application/json");
+            translated_unary_body(upstream_resp).await?
+        }
+        (false, true) => streaming_body(upstream_resp),
+        (false, false) => full_body(upstream_resp.bytes().await?),
+    };
+    Ok(response.body(body).unwrap())
 }
 
-pub async fn run_proxy(port: u16) -> Result<()> {
+pub async fn run_proxy(port: u16, cassettes: Option<CassetteMode>) -> Result<()> {
+    let mut runtime_files = daemon::PidFileGuard::default();
     let config = ProfileConfig::load()?;
-    let state = Arc::new(ProxyState::new(config));
+    let state = Arc::new(ProxyState {
+        cassettes,
+        ..ProxyState::new(config)
+    });
 
     let addr = SocketAddr::from(([127, 0, 0, 1], port));
     let listener = TcpListener::bind(addr).await?;
     println!("Proxy listening on http://127.0.0.1:{}", port);
+    let endpoint = AdminEndpoint {
+        url: format!("http://127.0.0.1:{}", port),
+        token: state.admin_token.clone(),
+    };
+    match endpoint.publish() {
+        Ok(()) => runtime_files.admin_endpoint = Some(endpoint),
+        Err(e) => eprintln!("Failed to publish admin endpoint: {:#}", e),
+    }
 
     #[cfg(unix)]
     {
@@ -232,25 +1243,73 @@ pub async fn run_proxy(port: u16) -> Result<()> {
         });
     }
 
-    loop {
-        let (stream, _) = listener.accept().await?;
+    let graceful = GracefulShutdown::new();
+    let shutdown = shutdown_signal();
+    tokio::pin!(shutdown);
+    let signal = loop {
+        let (stream, _) = tokio::select! {
+            accepted = listener.accept() => accepted?,
+            signal = &mut shutdown => break signal,
+        };
         let io = TokioIo::new(stream);
         let state = state.clone();
 
+        let conn = http1::Builder::new().serve_connection(
+            io,
+            service_fn(move |req| {
+                let state = state.clone();
+                handle_request(req, state)
+            }),
+        );
+        let conn = graceful.watch(conn);
         tokio::spawn(async move {
-            if let Err(e) = http1::Builder::new()
-                .serve_connection(
-                    io,
-                    service_fn(move |req| {
-                        let state = state.clone();
-                        handle_request(req, state)
-                    }),
-                )
-                .await
-            {
+            if let Err(e) = conn.await {
                 eprintln!("Connection error: {}", e);
             }
         });
+    };
+
+    // Stop accepting, let open requests and streams finish, and close idle
+    // keep-alive connections.
+    drop(listener);
+    let grace = state.config.read().await.shutdown_grace();
+    eprintln!(
+        "Received {}, no longer accepting connections. Waiting up to {}s for {} active request(s)...",
+        signal,
+        grace.as_secs(),
+        state.active.count()
+    );
+    tokio::select! {
+        drained = tokio::time::timeout(grace, graceful.shutdown()) => match drained {
+            Ok(()) => eprintln!("All requests finished. Exiting."),
+            Err(_) => eprintln!(
+                "Grace period elapsed with {} request(s) still active. Exiting.",
+                state.active.count()
+            ),
+        },
+        signal = shutdown_signal() => {
+            eprintln!("Received {} again, exiting without waiting.", signal)
+        }
+    }
+    Ok(())
+}
+
+/// Resolves on SIGTERM or SIGINT, naming the signal.
+async fn shutdown_signal() -> &'static str {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        let mut term = signal(SignalKind::terminate()).expect("Failed to register SIGTERM handler");
+        let mut int = signal(SignalKind::interrupt()).expect("Failed to register SIGINT handler");
+        tokio::select! {
+            _ = term.recv() => "SIGTERM",
+            _ = int.recv() => "SIGINT",
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = tokio::signal::ctrl_c().await;
+        "Ctrl-C"
     }
 }
 
@@ -271,6 +1330,7 @@ mod tests {
                         api_key: None,
                         auth_token: None,
                         models: None,
+                        ..Default::default()
                     },
                 ),
                 (
@@ -284,9 +1344,15 @@ mod tests {
                             sonnet: "glm-4.7".to_string(),
                             opus: "glm-4.7".to_string(),
                         }),
+                        ..Default::default()
                     },
                 ),
             ]),
+            transcript: None,
+            prices: HashMap::new(),
+            budget: None,
+            admin_token: None,
+            shutdown_grace_secs: None,
         }
     }
 
@@ -318,4 +1384,46 @@ mod tests {
         let config = config_fixture();
         assert!(resolve_route("/p/missing/v1/messages", &config).is_err());
     }
+
+    #[test]
+    fn provider_chain_follows_fallback_list() {
+        let mut config = config_fixture();
+        config.providers.get_mut("claude").unwrap().fallback = vec![
+            "glm".to_string(),
+            "missing".to_string(),
+            "claude".to_string(),
+        ];
+        let route = resolve_route("/v1/messages", &config).unwrap();
+        let chain: Vec<String> = provider_chain(&route, &config)
+            .into_iter()
+            .map(|(name, _)| name)
+            .collect();
+        assert_eq!(chain, ["claude", "glm"]);
+    }
+
+    #[test]
+    fn falls_back_only_on_server_errors() {
+        assert!(should_fall_back(reqwest::StatusCode::BAD_GATEWAY));
+        assert!(should_fall_back(
+            reqwest::StatusCode::from_u16(529).unwrap()
+        ));
+        assert!(!should_fall_back(reqwest::StatusCode::TOO_MANY_REQUESTS));
+        assert!(!should_fall_back(reqwest::StatusCode::BAD_REQUEST));
+    }
+
+    #[test]
+    fn detects_event_stream_responses() {
+        let mut headers = reqwest::header::HeaderMap::new();
+        assert!(!is_event_stream(&headers));
+        headers.insert(
+            reqwest::header::CONTENT_TYPE,
+            "text/event-stream; charset=utf-8".parse().unwrap(),
+        );
+        assert!(is_event_stream(&headers));
+        headers.insert(
+            reqwest::header::CONTENT_TYPE,
+            "application/json".parse().unwrap(),
+        );
+        assert!(!is_event_stream(&headers));
+    }
 }
@@ -0,0 +1,171 @@
+use reqwest::header::HeaderMap;
+use std::collections::hash_map::RandomState;
+use std::hash::BuildHasher;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::config::RetryPolicy;
+use crate::time::parse_rfc3339;
+
+const RATELIMIT_KINDS: [&str; 4] = ["requests", "tokens", "input-tokens", "output-tokens"];
+
+pub fn is_retryable(policy: &RetryPolicy, status: u16) -> bool {
+    policy.retry_on.contains(&status)
+}
+
+/// Exponential backoff for the retry following `attempt` (1-based), capped
+/// at `max_delay_ms` and randomized by `jitter`.
+pub fn backoff_delay(policy: &RetryPolicy, attempt: u32) -> Duration {
+    let exp = attempt.saturating_sub(1).min(16);
+    let delay = policy
+        .base_delay_ms
+        .saturating_mul(1u64 << exp)
+        .min(policy.max_delay_ms);
+    let jitter = policy.jitter.clamp(0.0, 1.0);
+    let factor = 1.0 - jitter * random_unit();
+    Duration::from_millis((delay as f64 * factor) as u64)
+}
+
+/// How long the upstream asked us to wait, from `retry-after-ms`,
+/// `retry-after` (seconds or HTTP date) or, failing those, the reset time of
+/// any exhausted `anthropic-ratelimit-*` bucket.
+pub fn server_hint(headers: &HeaderMap, now: SystemTime) -> Option<Duration> {
+    let header = |name: &str| headers.get(name).and_then(|v| v.to_str().ok());
+
+    if let Some(ms) = header("retry-after-ms").and_then(|v| v.trim().parse::<f64>().ok()) {
+        return Some(Duration::from_millis(ms.max(0.0) as u64));
+    }
+    if let Some(value) = header("retry-after") {
+        let value = value.trim();
+        if let Ok(secs) = value.parse::<f64>() {
+            return Some(Duration::from_millis((secs.max(0.0) * 1000.0) as u64));
+        }
+        if let Ok(at) = httpdate::parse_http_date(value) {
+            return Some(at.duration_since(now).unwrap_or_default());
+        }
+    }
+
+    RATELIMIT_KINDS
+        .iter()
+        .filter(|kind| {
+            header(&format!("anthropic-ratelimit-{}-remaining", kind))
+                .and_then(|v| v.trim().parse::<u64>().ok())
+                == Some(0)
+        })
+        .filter_map(|kind| header(&format!("anthropic-ratelimit-{}-reset", kind)))
+        .filter_map(parse_rfc3339)
+        .map(|at| at.duration_since(now).unwrap_or_default())
+        .max()
+}
+
+/// Delay before retrying after `attempt` (1-based). The server's hint wins
+/// over computed backoff; `None` means the hint exceeds `max_delay_ms` and
+/// the response should be surfaced rather than waited out.
+pub fn next_delay(policy: &RetryPolicy, attempt: u32, headers: &HeaderMap) -> Option<Duration> {
+    match server_hint(headers, SystemTime::now()) {
+        Some(hint) if hint > Duration::from_millis(policy.max_delay_ms) => None,
+        Some(hint) => Some(hint),
+        None => Some(backoff_delay(policy, attempt)),
+    }
+}
+
+fn random_unit() -> f64 {
+    let seed = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .subsec_nanos();
+    RandomState::new().hash_one(seed) as f64 / u64::MAX as f64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn headers(pairs: &[(&'static str, &str)]) -> HeaderMap {
+        let mut map = HeaderMap::new();
+        for (name, value) in pairs {
+            map.insert(*name, value.parse().unwrap());
+        }
+        map
+    }
+
+    #[test]
+    fn backoff_doubles_and_caps() {
+        let policy = RetryPolicy {
+            base_delay_ms: 100,
+            max_delay_ms: 350,
+            jitter: 0.0,
+            ..Default::default()
+        };
+        assert_eq!(backoff_delay(&policy, 1), Duration::from_millis(100));
+        assert_eq!(backoff_delay(&policy, 2), Duration::from_millis(200));
+        assert_eq!(backoff_delay(&policy, 3), Duration::from_millis(350));
+    }
+
+    #[test]
+    fn jitter_only_shortens_delay() {
+        let policy = RetryPolicy {
+            base_delay_ms: 1000,
+            jitter: 0.5,
+            ..Default::default()
+        };
+        for _ in 0..20 {
+            let delay = backoff_delay(&policy, 1);
+            assert!(delay >= Duration::from_millis(500) && delay <= Duration::from_millis(1000));
+        }
+    }
+
+    #[test]
+    fn retry_after_seconds_and_ms() {
+        let now = SystemTime::now();
+        assert_eq!(
+            server_hint(&headers(&[("retry-after", "2")]), now),
+            Some(Duration::from_secs(2))
+        );
+        assert_eq!(
+            server_hint(&headers(&[("retry-after-ms", "150")]), now),
+            Some(Duration::from_millis(150))
+        );
+        assert_eq!(server_hint(&HeaderMap::new(), now), None);
+    }
+
+    #[test]
+    fn retry_after_http_date() {
+        let now = UNIX_EPOCH + Duration::from_secs(784_111_777);
+        let hint = server_hint(
+            &headers(&[("retry-after", "Sun, 06 Nov 1994 08:49:47 GMT")]),
+            now,
+        );
+        assert_eq!(hint, Some(Duration::from_secs(10)));
+    }
+
+    #[test]
+    fn anthropic_ratelimit_reset_of_exhausted_bucket() {
+        let now = parse_rfc3339("2025-03-01T12:00:00Z").unwrap();
+        let map = headers(&[
+            ("anthropic-ratelimit-requests-remaining", "10"),
+            ("anthropic-ratelimit-requests-reset", "2025-03-01T12:05:00Z"),
+            ("anthropic-ratelimit-tokens-remaining", "0"),
+            (
+                "anthropic-ratelimit-tokens-reset",
+                "2025-03-01T13:00:07+01:00",
+            ),
+        ]);
+        assert_eq!(server_hint(&map, now), Some(Duration::from_secs(7)));
+    }
+
+    #[test]
+    fn long_hint_stops_retrying() {
+        let policy = RetryPolicy {
+            max_delay_ms: 1000,
+            ..Default::default()
+        };
+        assert_eq!(
+            next_delay(&policy, 1, &headers(&[("retry-after", "60")])),
+            None
+        );
+        assert_eq!(
+            next_delay(&policy, 1, &headers(&[("retry-after", "0.5")])),
+            Some(Duration::from_millis(500))
+        );
+    }
+}
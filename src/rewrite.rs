@@ -1,6 +1,6 @@
 use crate::config::Provider;
 
-fn classify_model(model: &str) -> Option<&'static str> {
+pub fn classify_model(model: &str) -> Option<&'static str> {
     let lower = model.to_lowercase();
     if lower.contains("haiku") {
         Some("haiku")
@@ -42,6 +42,7 @@ mod tests {
                 sonnet: "glm-4.7".into(),
                 opus: "glm-4.7".into(),
             }),
+            ..Default::default()
         }
     }
 
@@ -51,6 +52,7 @@ mod tests {
             api_key: None,
             auth_token: None,
             models: None,
+            ..Default::default()
         }
     }
 
@@ -0,0 +1,107 @@
+//! Minimal UTC timestamp helpers, enough for rate-limit headers and log lines.
+
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Parse RFC 3339 timestamps such as those in `anthropic-ratelimit-*-reset`,
+/// e.g. `2025-01-01T00:00:30Z` or `2025-01-01T00:00:30.5+01:00`.
+pub fn parse_rfc3339(value: &str) -> Option<SystemTime> {
+    let value = value.trim();
+    let (date, rest) = value.split_once(['T', 't', ' '])?;
+    let mut date_parts = date.splitn(3, '-');
+    let year: i64 = date_parts.next()?.parse().ok()?;
+    let month: u32 = date_parts.next()?.parse().ok()?;
+    let day: u32 = date_parts.next()?.parse().ok()?;
+
+    let (time, offset_secs) = if let Some(time) = rest.strip_suffix(['Z', 'z']) {
+        (time, 0i64)
+    } else {
+        let split = rest.rfind(['+', '-'])?;
+        let (time, offset) = rest.split_at(split);
+        let sign = if offset.starts_with('-') { -1 } else { 1 };
+        let (h, m) = offset[1..].split_once(':')?;
+        let offset = h.parse::<i64>().ok()? * 3600 + m.parse::<i64>().ok()? * 60;
+        (time, sign * offset)
+    };
+    let mut time_parts = time.splitn(3, ':');
+    let hour: i64 = time_parts.next()?.parse().ok()?;
+    let minute: i64 = time_parts.next()?.parse().ok()?;
+    let seconds: f64 = time_parts.next()?.parse().ok()?;
+
+    let days = days_from_civil(year, month, day);
+    let secs = days * 86_400 + hour * 3600 + minute * 60 - offset_secs;
+    let millis = secs as f64 * 1000.0 + seconds * 1000.0;
+    if millis < 0.0 {
+        return None;
+    }
+    Some(UNIX_EPOCH + Duration::from_millis(millis as u64))
+}
+
+/// Format as an RFC 3339 UTC timestamp with millisecond precision.
+pub fn format_rfc3339(at: SystemTime) -> String {
+    let since_epoch = at.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = since_epoch.as_secs() as i64;
+    let (year, month, day) = civil_from_days(secs.div_euclid(86_400));
+    let rem = secs.rem_euclid(86_400);
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year,
+        month,
+        day,
+        rem / 3600,
+        rem % 3600 / 60,
+        rem % 60,
+        since_epoch.subsec_millis()
+    )
+}
+
+/// The UTC calendar day, as `YYYY-MM-DD`.
+pub fn format_date(at: SystemTime) -> String {
+    format_rfc3339(at)[..10].to_string()
+}
+
+/// Days since 1970-01-01 for a proleptic Gregorian date.
+fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let yoe = year - era * 400;
+    let month = month as i64;
+    let doy = (153 * (if month > 2 { month - 3 } else { month + 9 }) + 2) / 5 + day as i64 - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146_097 + doe - 719_468
+}
+
+/// Inverse of [`days_from_civil`].
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rfc3339_parsing() {
+        assert_eq!(
+            parse_rfc3339("1970-01-02T00:00:01.5Z"),
+            Some(UNIX_EPOCH + Duration::from_millis(86_401_500))
+        );
+        assert_eq!(parse_rfc3339("not a date"), None);
+    }
+
+    #[test]
+    fn rfc3339_roundtrip() {
+        let at = parse_rfc3339("2024-02-29T23:59:58.250Z").unwrap();
+        assert_eq!(format_rfc3339(at), "2024-02-29T23:59:58.250Z");
+        assert_eq!(format_rfc3339(UNIX_EPOCH), "1970-01-01T00:00:00.000Z");
+        assert_eq!(format_date(at), "2024-02-29");
+    }
+}
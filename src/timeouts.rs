@@ -0,0 +1,191 @@
+//! Per-provider upstream timeouts. The connect timeout lives on the
+//! provider's HTTP client; the others are enforced here around each attempt.
+
+use anyhow::Result;
+use futures_util::StreamExt;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::config::Timeouts;
+use crate::metrics::Metrics;
+use crate::proxy::{BoxError, ProxyError};
+
+/// The limits for one attempt, measured from when it started.
+#[derive(Debug, Clone, Copy)]
+pub struct Deadlines {
+    first_byte: Duration,
+    idle_stream: Duration,
+    total: Option<(Instant, Duration)>,
+}
+
+impl Deadlines {
+    pub fn start(timeouts: &Timeouts) -> Self {
+        Self {
+            first_byte: Duration::from_millis(timeouts.first_byte_timeout_ms),
+            idle_stream: Duration::from_millis(timeouts.idle_stream_timeout_ms),
+            total: timeouts.total_timeout_ms.map(|ms| {
+                let total = Duration::from_millis(ms);
+                (Instant::now() + total, total)
+            }),
+        }
+    }
+
+    /// `limit`, cut short if the total deadline comes first. Also says which
+    /// setting the returned duration enforces.
+    fn within_total(&self, limit: Duration, setting: &'static str) -> (Duration, &'static str) {
+        match self.total {
+            Some((deadline, _)) if deadline.saturating_duration_since(Instant::now()) < limit => (
+                deadline.saturating_duration_since(Instant::now()),
+                "total_timeout_ms",
+            ),
+            _ => (limit, setting),
+        }
+    }
+
+    fn error(&self, provider: &str, waiting_for: &str, setting: &'static str) -> anyhow::Error {
+        let limit = match (setting, self.total) {
+            ("total_timeout_ms", Some((_, total))) => total,
+            ("idle_stream_timeout_ms", _) => self.idle_stream,
+            _ => self.first_byte,
+        };
+        ProxyError {
+            status: 504,
+            error_type: "timeout_error",
+            message: format!(
+                "{} timed out waiting for {} after {}s ({})",
+                provider,
+                waiting_for,
+                limit.as_secs_f64(),
+                setting
+            ),
+        }
+        .into()
+    }
+
+    /// Run `send` until the response headers arrive, or fail with a 504.
+    pub async fn first_byte<T>(
+        &self,
+        provider: &str,
+        send: impl std::future::Future<Output = Result<T>>,
+    ) -> Result<T> {
+        let (limit, setting) = self.within_total(self.first_byte, "first_byte_timeout_ms");
+        match tokio::time::timeout(limit, send).await {
+            Ok(result) => result,
+            Err(_) => Err(self.error(provider, "response headers", setting)),
+        }
+    }
+
+    /// Pass the body through, ending it with an error if the upstream goes
+    /// quiet for too long or the total deadline passes.
+    pub fn watch_body(
+        self,
+        provider: &str,
+        response: reqwest::Response,
+        metrics: Arc<Metrics>,
+    ) -> Result<reqwest::Response> {
+        let provider = provider.to_string();
+        let mut builder = hyper::Response::builder()
+            .status(response.status())
+            .version(response.version());
+        if let Some(headers) = builder.headers_mut() {
+            *headers = response.headers().clone();
+        }
+
+        let stream = futures_util::stream::unfold(Some(response.bytes_stream()), move |upstream| {
+            let provider = provider.clone();
+            let metrics = metrics.clone();
+            async move {
+                let mut upstream = upstream?;
+                let (limit, setting) =
+                    self.within_total(self.idle_stream, "idle_stream_timeout_ms");
+                match tokio::time::timeout(limit, upstream.next()).await {
+                    Ok(Some(chunk)) => Some((chunk.map_err(BoxError::from), Some(upstream))),
+                    Ok(None) => None,
+                    Err(_) => {
+                        let error = self.error(&provider, "the response body", setting);
+                        eprintln!("[proxy] {:#}; closing the upstream connection", error);
+                        metrics.record_upstream_error(&provider, "timeout");
+                        Some((Err(BoxError::from(format!("{:#}", error))), None))
+                    }
+                }
+            }
+        });
+        Ok(reqwest::Response::from(
+            builder.body(reqwest::Body::wrap_stream(stream))?,
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::Bytes;
+
+    fn slow_body(gaps_ms: &'static [u64]) -> reqwest::Response {
+        let chunks = futures_util::stream::iter(gaps_ms).then(|&gap| async move {
+            tokio::time::sleep(Duration::from_millis(gap)).await;
+            Ok::<_, BoxError>(Bytes::from_static(b"data: {}\n\n"))
+        });
+        reqwest::Response::from(
+            hyper::Response::builder()
+                .status(200)
+                .body(reqwest::Body::wrap_stream(chunks))
+                .unwrap(),
+        )
+    }
+
+    fn timeouts(idle_ms: u64, total_ms: Option<u64>) -> Timeouts {
+        Timeouts {
+            idle_stream_timeout_ms: idle_ms,
+            total_timeout_ms: total_ms,
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn idle_stream_timeout_cuts_a_stalled_body() {
+        let metrics = Arc::new(Metrics::default());
+        let deadlines = Deadlines::start(&timeouts(100, None));
+        let response = deadlines
+            .watch_body("glm", slow_body(&[0, 20, 500]), metrics.clone())
+            .unwrap();
+        let err = response.bytes().await.unwrap_err();
+        assert!(format!("{:?}", err).contains("idle_stream_timeout_ms"));
+        assert!(metrics
+            .render()
+            .contains("cms_upstream_errors_total{provider=\"glm\",reason=\"timeout\"} 1"));
+
+        let deadlines = Deadlines::start(&timeouts(100, None));
+        let response = deadlines
+            .watch_body("glm", slow_body(&[0, 20, 20]), metrics)
+            .unwrap();
+        assert_eq!(response.bytes().await.unwrap().len(), 30);
+    }
+
+    #[tokio::test]
+    async fn total_timeout_applies_across_phases() {
+        let deadlines = Deadlines::start(&timeouts(1_000, Some(150)));
+        let slow_headers = async {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            Ok(())
+        };
+        deadlines.first_byte("glm", slow_headers).await.unwrap();
+        let err = deadlines
+            .watch_body("glm", slow_body(&[30, 30, 30]), Arc::default())
+            .unwrap()
+            .bytes()
+            .await
+            .unwrap_err();
+        assert!(format!("{:?}", err).contains("total_timeout_ms"));
+
+        let never = std::future::pending::<Result<()>>();
+        let err = Deadlines::start(&Timeouts {
+            first_byte_timeout_ms: 50,
+            ..Default::default()
+        })
+        .first_byte("glm", never)
+        .await
+        .unwrap_err();
+        assert_eq!(err.downcast_ref::<ProxyError>().unwrap().status, 504);
+    }
+}
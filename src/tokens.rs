@@ -0,0 +1,164 @@
+//! A bundled tokenizer approximation, for answering `count_tokens` locally
+//! and for checking requests against a provider's context limit.
+
+use serde_json::Value;
+
+use crate::config::TokenCounting;
+
+/// Role and separator tokens around each message.
+const MESSAGE_TOKENS: u64 = 3;
+/// The system prompt Anthropic adds when a request defines tools.
+const TOOL_USE_SYSTEM_TOKENS: u64 = 346;
+
+/// CJK and other wide characters are roughly a token each.
+fn is_wide(c: char) -> bool {
+    c >= '\u{2E80}'
+}
+
+fn word_tokens(chars: usize, chars_per_token: f64) -> u64 {
+    (chars as f64 / chars_per_token).round().max(1.0) as u64
+}
+
+/// Tokens in `text`. Runs of letters and digits are words costing one
+/// token per `chars_per_token` characters, other symbols cost one each, and
+/// whitespace is folded into the following word.
+pub fn text_tokens(text: &str, chars_per_token: f64) -> u64 {
+    let mut tokens = 0;
+    let mut word = 0;
+    for c in text.chars() {
+        if c.is_alphanumeric() && !is_wide(c) {
+            word += 1;
+            continue;
+        }
+        if word > 0 {
+            tokens += word_tokens(word, chars_per_token);
+            word = 0;
+        }
+        if !c.is_whitespace() {
+            tokens += 1;
+        }
+    }
+    if word > 0 {
+        tokens += word_tokens(word, chars_per_token);
+    }
+    tokens
+}
+
+fn content_tokens(content: Option<&Value>, counting: &TokenCounting) -> u64 {
+    match content {
+        Some(Value::String(text)) => text_tokens(text, counting.chars_per_token),
+        Some(Value::Array(blocks)) => blocks.iter().map(|b| block_tokens(b, counting)).sum(),
+        _ => 0,
+    }
+}
+
+fn field_tokens(value: &Value, field: &str, counting: &TokenCounting) -> u64 {
+    value
+        .get(field)
+        .and_then(Value::as_str)
+        .map_or(0, |text| text_tokens(text, counting.chars_per_token))
+}
+
+fn block_tokens(block: &Value, counting: &TokenCounting) -> u64 {
+    let json_tokens = |value: Option<&Value>| {
+        value.map_or(0, |v| text_tokens(&v.to_string(), counting.chars_per_token))
+    };
+    match block.get("type").and_then(Value::as_str) {
+        Some("text") => field_tokens(block, "text", counting),
+        Some("thinking") => field_tokens(block, "thinking", counting),
+        Some("redacted_thinking") => 0,
+        Some("image") => counting.image_tokens,
+        Some("tool_use" | "server_tool_use") => {
+            field_tokens(block, "name", counting) + json_tokens(block.get("input"))
+        }
+        Some("tool_result") => content_tokens(block.get("content"), counting),
+        // Text documents count their text; PDFs and URLs a flat image's worth.
+        Some("document") => match block.pointer("/source/type").and_then(Value::as_str) {
+            Some("text") => field_tokens(&block["source"], "data", counting),
+            Some("content") => content_tokens(block.pointer("/source/content"), counting),
+            _ => counting.image_tokens,
+        },
+        _ => json_tokens(Some(block)),
+    }
+}
+
+/// Estimated input tokens for a Messages request: its system prompt, tool
+/// definitions and messages. `model` and `max_tokens` cost nothing.
+pub fn count_input_tokens(body: &Value, counting: &TokenCounting) -> u64 {
+    let system = content_tokens(body.get("system"), counting);
+    let tools = match body.get("tools").and_then(Value::as_array) {
+        Some(tools) if !tools.is_empty() => {
+            TOOL_USE_SYSTEM_TOKENS
+                + tools
+                    .iter()
+                    .map(|tool| text_tokens(&tool.to_string(), counting.chars_per_token))
+                    .sum::<u64>()
+        }
+        _ => 0,
+    };
+    let messages: u64 = body
+        .get("messages")
+        .and_then(Value::as_array)
+        .into_iter()
+        .flatten()
+        .map(|message| MESSAGE_TOKENS + content_tokens(message.get("content"), counting))
+        .sum();
+    system + tools + messages
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn tokenizes_words_symbols_and_wide_characters() {
+        assert_eq!(text_tokens("", 4.0), 0);
+        assert_eq!(text_tokens("Hello, world!", 4.0), 4);
+        assert_eq!(text_tokens("internationalization", 4.0), 5);
+        assert_eq!(text_tokens("fn main() {}", 4.0), 6);
+        assert_eq!(text_tokens("你好世界", 4.0), 4);
+        assert_eq!(text_tokens("internationalization", 2.0), 10);
+    }
+
+    #[test]
+    fn counts_system_tools_images_and_messages() {
+        let counting = TokenCounting::default();
+        let plain = json!({
+            "model": "claude-sonnet-4-5-20250929",
+            "max_tokens": 1024,
+            "messages": [{"role": "user", "content": "Hello there"}]
+        });
+        assert_eq!(count_input_tokens(&plain, &counting), MESSAGE_TOKENS + 2);
+
+        let full = json!({
+            "system": [{"type": "text", "text": "Be brief"}],
+            "tools": [{"name": "read", "description": "Read a file", "input_schema": {"type": "object"}}],
+            "messages": [
+                {"role": "user", "content": [
+                    {"type": "image", "source": {"type": "base64", "media_type": "image/png", "data": "A".repeat(40_000)}},
+                    {"type": "text", "text": "what is this?"}
+                ]},
+                {"role": "assistant", "content": [
+                    {"type": "tool_use", "id": "t1", "name": "read", "input": {"path": "a"}}
+                ]},
+                {"role": "user", "content": [
+                    {"type": "tool_result", "tool_use_id": "t1", "content": "file body"}
+                ]}
+            ]
+        });
+        let total = count_input_tokens(&full, &counting);
+        let without_image = total - counting.image_tokens;
+        assert!(total > TOOL_USE_SYSTEM_TOKENS + counting.image_tokens);
+        assert!(without_image < TOOL_USE_SYSTEM_TOKENS + 100);
+
+        let cheap_images = TokenCounting {
+            image_tokens: 100,
+            ..Default::default()
+        };
+        assert_eq!(
+            count_input_tokens(&full, &cheap_images),
+            without_image + 100
+        );
+    }
+}
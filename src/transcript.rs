@@ -0,0 +1,162 @@
+//! Opt-in JSONL transcript of proxied exchanges, written under `~/.claude/`.
+
+use anyhow::{Context, Result};
+use hyper::HeaderMap;
+use serde_json::{Map, Value};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use crate::config::TranscriptConfig;
+
+const REDACTED: &str = "[REDACTED]";
+
+/// Headers whose values are credentials no matter what they contain.
+const SENSITIVE_HEADERS: [&str; 4] = [
+    "authorization",
+    "x-api-key",
+    "proxy-authorization",
+    "cookie",
+];
+
+/// Resolved transcript settings for one request, captured while the config
+/// read lock is held.
+#[derive(Debug, Clone)]
+pub struct TranscriptSettings {
+    pub path: PathBuf,
+    pub include_bodies: bool,
+    pub secrets: Vec<String>,
+}
+
+impl TranscriptSettings {
+    pub fn resolve(config: &TranscriptConfig, secrets: Vec<String>) -> Result<Self> {
+        let path = match &config.path {
+            Some(path) => path.clone(),
+            None => default_path()?,
+        };
+        Ok(Self {
+            path,
+            include_bodies: config.include_bodies,
+            secrets,
+        })
+    }
+}
+
+pub fn default_path() -> Result<PathBuf> {
+    let home = dirs::home_dir().context("Could not find home directory")?;
+    Ok(home.join(".claude").join("model-switch-transcript.jsonl"))
+}
+
+/// Replace every occurrence of a configured secret in `text`.
+pub fn redact_text(text: &str, secrets: &[String]) -> String {
+    secrets.iter().fold(text.to_string(), |acc, secret| {
+        acc.replace(secret.as_str(), REDACTED)
+    })
+}
+
+/// Recursively redact configured secrets from every string in a JSON value.
+pub fn redact_value(value: &mut Value, secrets: &[String]) {
+    match value {
+        Value::String(s) if secrets.iter().any(|secret| s.contains(secret.as_str())) => {
+            *s = redact_text(s, secrets);
+        }
+        Value::Array(items) => items.iter_mut().for_each(|v| redact_value(v, secrets)),
+        Value::Object(map) => map.values_mut().for_each(|v| redact_value(v, secrets)),
+        _ => {}
+    }
+}
+
+/// Headers as a JSON object with credential headers masked and secrets
+/// scrubbed from the rest.
+pub fn redact_headers(headers: &HeaderMap, secrets: &[String]) -> Value {
+    let mut out = Map::new();
+    for (name, value) in headers {
+        let value = if SENSITIVE_HEADERS.contains(&name.as_str()) {
+            REDACTED.to_string()
+        } else {
+            redact_text(&String::from_utf8_lossy(value.as_bytes()), secrets)
+        };
+        out.insert(name.as_str().to_string(), Value::String(value));
+    }
+    Value::Object(out)
+}
+
+/// A body for the transcript: parsed JSON when possible, otherwise text.
+/// Secrets are redacted either way.
+pub fn body_value(bytes: &[u8], secrets: &[String]) -> Value {
+    let mut value = serde_json::from_slice::<Value>(bytes)
+        .unwrap_or_else(|_| Value::String(String::from_utf8_lossy(bytes).into_owned()));
+    redact_value(&mut value, secrets);
+    value
+}
+
+pub fn append(path: &Path, record: &Value) -> Result<()> {
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    let mut line = serde_json::to_vec(record)?;
+    line.push(b'\n');
+    // Transcripts may hold request bodies, so only the owner can read them.
+    let mut options = std::fs::OpenOptions::new();
+    options.create(true).append(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    let mut file = options
+        .open(path)
+        .with_context(|| format!("Failed to open transcript {}", path.display()))?;
+    file.write_all(&line)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn credential_headers_are_always_masked() {
+        let mut headers = HeaderMap::new();
+        headers.insert("x-api-key", "sk-inbound".parse().unwrap());
+        headers.insert("authorization", "Bearer tok".parse().unwrap());
+        headers.insert("x-custom", "prefix-sk-secret".parse().unwrap());
+        headers.insert("anthropic-version", "2023-06-01".parse().unwrap());
+        let out = redact_headers(&headers, &["sk-secret".to_string()]);
+        assert_eq!(out["x-api-key"], REDACTED);
+        assert_eq!(out["authorization"], REDACTED);
+        assert_eq!(out["x-custom"], "prefix-[REDACTED]");
+        assert_eq!(out["anthropic-version"], "2023-06-01");
+    }
+
+    #[test]
+    fn secrets_are_scrubbed_from_bodies() {
+        let secrets = vec!["sk-live-123".to_string()];
+        let body = br#"{"messages":[{"content":"my key is sk-live-123"}]}"#;
+        let out = body_value(body, &secrets);
+        assert_eq!(
+            out,
+            json!({"messages": [{"content": "my key is [REDACTED]"}]})
+        );
+        assert_eq!(
+            body_value(b"data: sk-live-123\n", &secrets),
+            json!("data: [REDACTED]\n")
+        );
+    }
+
+    #[test]
+    fn append_writes_one_line_per_record() {
+        let dir = std::env::temp_dir().join(format!(
+            "cms-transcript-test-{}-{:?}",
+            std::process::id(),
+            std::thread::current().id()
+        ));
+        let path = dir.join("t.jsonl");
+        let _ = std::fs::remove_file(&path);
+        append(&path, &json!({"a": 1})).unwrap();
+        append(&path, &json!({"b": 2})).unwrap();
+        let content = std::fs::read_to_string(&path).unwrap();
+        assert_eq!(content, "{\"a\":1}\n{\"b\":2}\n");
+        let _ = std::fs::remove_dir_all(dir);
+    }
+}
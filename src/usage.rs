@@ -0,0 +1,523 @@
+//! Append-only token usage store and the `usage` report built on it.
+
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::io::{BufRead, Write};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use crate::config::{ModelPrice, ProfileConfig};
+use crate::exchange::Usage;
+use crate::time::{format_date, format_rfc3339};
+
+/// One proxied response with reported usage.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct UsageRecord {
+    pub timestamp: String,
+    /// UTC day, `YYYY-MM-DD`.
+    pub day: String,
+    pub provider: String,
+    /// Model ID sent upstream.
+    pub model: String,
+    /// Claude tier the client asked for, if the requested model had one.
+    #[serde(default)]
+    pub tier: Option<String>,
+    pub input_tokens: u64,
+    pub output_tokens: u64,
+    #[serde(default)]
+    pub cache_creation_input_tokens: u64,
+    #[serde(default)]
+    pub cache_read_input_tokens: u64,
+}
+
+impl UsageRecord {
+    pub fn new(
+        at: SystemTime,
+        provider: &str,
+        model: &str,
+        tier: Option<&str>,
+        usage: &Usage,
+    ) -> Self {
+        Self {
+            timestamp: format_rfc3339(at),
+            day: format_date(at),
+            provider: provider.to_string(),
+            model: model.to_string(),
+            tier: tier.map(ToString::to_string),
+            input_tokens: usage.input_tokens,
+            output_tokens: usage.output_tokens,
+            cache_creation_input_tokens: usage.cache_creation_input_tokens,
+            cache_read_input_tokens: usage.cache_read_input_tokens,
+        }
+    }
+
+    /// Cost in USD, or `None` when the model has no price configured.
+    pub fn cost(&self, prices: &std::collections::HashMap<String, ModelPrice>) -> Option<f64> {
+        let price = prices.get(&self.model)?;
+        let per_token = |tokens: u64, per_mtok: f64| tokens as f64 * per_mtok / 1_000_000.0;
+        Some(
+            per_token(self.input_tokens, price.input)
+                + per_token(self.output_tokens, price.output)
+                + per_token(
+                    self.cache_creation_input_tokens,
+                    price.cache_write.unwrap_or(price.input),
+                )
+                + per_token(
+                    self.cache_read_input_tokens,
+                    price.cache_read.unwrap_or(price.input),
+                ),
+        )
+    }
+}
+
+pub fn default_path() -> Result<PathBuf> {
+    let home = dirs::home_dir().context("Could not find home directory")?;
+    Ok(home.join(".claude").join("model-switch-usage.jsonl"))
+}
+
+pub fn append(path: &Path, record: &UsageRecord) -> Result<()> {
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    let mut line = serde_json::to_vec(record)?;
+    line.push(b'\n');
+    let mut file = std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .with_context(|| format!("Failed to open usage store {}", path.display()))?;
+    file.write_all(&line)?;
+    Ok(())
+}
+
+/// Read every record, skipping lines that fail to parse (e.g. a partial
+/// write from a crash).
+pub fn load(path: &Path) -> Result<Vec<UsageRecord>> {
+    if !path.exists() {
+        return Ok(Vec::new());
+    }
+    let file = std::fs::File::open(path)
+        .with_context(|| format!("Failed to read usage store {}", path.display()))?;
+    let mut records = Vec::new();
+    for line in std::io::BufReader::new(file).lines() {
+        if let Ok(record) = serde_json::from_str::<UsageRecord>(&line?) {
+            records.push(record);
+        }
+    }
+    Ok(records)
+}
+
+/// Columns the report can group by.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GroupBy {
+    Provider,
+    Model,
+    Tier,
+    Day,
+}
+
+impl std::str::FromStr for GroupBy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "provider" => Ok(GroupBy::Provider),
+            "model" => Ok(GroupBy::Model),
+            "tier" => Ok(GroupBy::Tier),
+            "day" | "date" => Ok(GroupBy::Day),
+            _ => bail!(
+                "Unknown grouping '{}'. Supported: provider, model, tier, day",
+                s
+            ),
+        }
+    }
+}
+
+impl GroupBy {
+    fn name(self) -> &'static str {
+        match self {
+            GroupBy::Provider => "provider",
+            GroupBy::Model => "model",
+            GroupBy::Tier => "tier",
+            GroupBy::Day => "day",
+        }
+    }
+
+    fn key(self, record: &UsageRecord) -> String {
+        match self {
+            GroupBy::Provider => record.provider.clone(),
+            GroupBy::Model => record.model.clone(),
+            GroupBy::Tier => record.tier.clone().unwrap_or_else(|| "-".to_string()),
+            GroupBy::Day => record.day.clone(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutputFormat {
+    Table,
+    Json,
+    Csv,
+}
+
+impl std::str::FromStr for OutputFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "table" => Ok(OutputFormat::Table),
+            "json" => Ok(OutputFormat::Json),
+            "csv" => Ok(OutputFormat::Csv),
+            _ => bail!("Unknown format '{}'. Supported: table, json, csv", s),
+        }
+    }
+}
+
+/// Inclusive day range and provider filter for a report.
+#[derive(Debug, Default)]
+pub struct UsageFilter {
+    pub since: Option<String>,
+    pub until: Option<String>,
+    pub provider: Option<String>,
+}
+
+impl UsageFilter {
+    fn matches(&self, record: &UsageRecord) -> bool {
+        // `YYYY-MM-DD` strings order the same as the dates they name.
+        self.since
+            .as_deref()
+            .is_none_or(|s| record.day.as_str() >= s)
+            && self
+                .until
+                .as_deref()
+                .is_none_or(|u| record.day.as_str() <= u)
+            && self
+                .provider
+                .as_deref()
+                .is_none_or(|p| record.provider == p)
+    }
+}
+
+fn validate_day(value: &str, flag: &str) -> Result<()> {
+    let ok = value.len() == 10
+        && value.chars().enumerate().all(|(i, c)| match i {
+            4 | 7 => c == '-',
+            _ => c.is_ascii_digit(),
+        });
+    if !ok {
+        bail!(
+            "{} must be a date in YYYY-MM-DD format, got '{}'",
+            flag,
+            value
+        );
+    }
+    Ok(())
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Serialize)]
+pub struct UsageTotals {
+    pub requests: u64,
+    pub input_tokens: u64,
+    pub output_tokens: u64,
+    pub cache_creation_input_tokens: u64,
+    pub cache_read_input_tokens: u64,
+    /// Summed cost of priced requests; `None` if none were priced.
+    pub cost_usd: Option<f64>,
+    /// Requests whose model has no entry in the price table.
+    pub unpriced_requests: u64,
+}
+
+impl UsageTotals {
+    pub fn add(&mut self, record: &UsageRecord, cost: Option<f64>) {
+        self.requests += 1;
+        self.input_tokens += record.input_tokens;
+        self.output_tokens += record.output_tokens;
+        self.cache_creation_input_tokens += record.cache_creation_input_tokens;
+        self.cache_read_input_tokens += record.cache_read_input_tokens;
+        match cost {
+            Some(cost) => *self.cost_usd.get_or_insert(0.0) += cost,
+            None => self.unpriced_requests += 1,
+        }
+    }
+}
+
+/// Aggregate matching records, keyed by the grouping columns in order.
+pub fn summarize(
+    records: &[UsageRecord],
+    filter: &UsageFilter,
+    group_by: &[GroupBy],
+    config: &ProfileConfig,
+) -> BTreeMap<Vec<String>, UsageTotals> {
+    let mut rows: BTreeMap<Vec<String>, UsageTotals> = BTreeMap::new();
+    for record in records.iter().filter(|r| filter.matches(r)) {
+        let key = group_by.iter().map(|g| g.key(record)).collect();
+        rows.entry(key)
+            .or_default()
+            .add(record, record.cost(&config.prices));
+    }
+    rows
+}
+
+fn format_cost(cost: Option<f64>) -> String {
+    cost.map(|c| format!("{:.4}", c))
+        .unwrap_or_else(|| "n/a".to_string())
+}
+
+fn render_table(group_by: &[GroupBy], rows: &BTreeMap<Vec<String>, UsageTotals>) -> String {
+    let mut header: Vec<String> = group_by.iter().map(|g| g.name().to_string()).collect();
+    header.extend(
+        [
+            "requests",
+            "input",
+            "output",
+            "cache_write",
+            "cache_read",
+            "cost_usd",
+        ]
+        .map(String::from),
+    );
+    let mut lines = vec![header];
+    let mut total = UsageTotals::default();
+    for (key, totals) in rows {
+        let mut line = key.clone();
+        line.extend(totals_columns(totals));
+        lines.push(line);
+        total.requests += totals.requests;
+        total.input_tokens += totals.input_tokens;
+        total.output_tokens += totals.output_tokens;
+        total.cache_creation_input_tokens += totals.cache_creation_input_tokens;
+        total.cache_read_input_tokens += totals.cache_read_input_tokens;
+        total.unpriced_requests += totals.unpriced_requests;
+        if let Some(cost) = totals.cost_usd {
+            *total.cost_usd.get_or_insert(0.0) += cost;
+        }
+    }
+    let mut total_line = vec![String::new(); group_by.len()];
+    if let Some(first) = total_line.first_mut() {
+        *first = "TOTAL".to_string();
+    }
+    total_line.extend(totals_columns(&total));
+    lines.push(total_line);
+
+    let widths: Vec<usize> = (0..lines[0].len())
+        .map(|i| lines.iter().map(|l| l[i].len()).max().unwrap_or(0))
+        .collect();
+    let mut out = String::new();
+    for line in lines {
+        let cells: Vec<String> = line
+            .iter()
+            .enumerate()
+            .map(|(i, cell)| {
+                if i < group_by.len() {
+                    format!("{:<width$}", cell, width = widths[i])
+                } else {
+                    format!("{:>width$}", cell, width = widths[i])
+                }
+            })
+            .collect();
+        out.push_str(cells.join("  ").trim_end());
+        out.push('\n');
+    }
+    out
+}
+
+fn totals_columns(totals: &UsageTotals) -> Vec<String> {
+    vec![
+        totals.requests.to_string(),
+        totals.input_tokens.to_string(),
+        totals.output_tokens.to_string(),
+        totals.cache_creation_input_tokens.to_string(),
+        totals.cache_read_input_tokens.to_string(),
+        format_cost(totals.cost_usd),
+    ]
+}
+
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+fn render_csv(group_by: &[GroupBy], rows: &BTreeMap<Vec<String>, UsageTotals>) -> String {
+    let mut header: Vec<&str> = group_by.iter().map(|g| g.name()).collect();
+    header.extend([
+        "requests",
+        "input_tokens",
+        "output_tokens",
+        "cache_creation_input_tokens",
+        "cache_read_input_tokens",
+        "cost_usd",
+        "unpriced_requests",
+    ]);
+    let mut out = header.join(",");
+    out.push('\n');
+    for (key, totals) in rows {
+        let mut fields: Vec<String> = key.iter().map(|k| csv_field(k)).collect();
+        fields.extend([
+            totals.requests.to_string(),
+            totals.input_tokens.to_string(),
+            totals.output_tokens.to_string(),
+            totals.cache_creation_input_tokens.to_string(),
+            totals.cache_read_input_tokens.to_string(),
+            totals.cost_usd.map(|c| c.to_string()).unwrap_or_default(),
+            totals.unpriced_requests.to_string(),
+        ]);
+        out.push_str(&fields.join(","));
+        out.push('\n');
+    }
+    out
+}
+
+fn render_json(group_by: &[GroupBy], rows: &BTreeMap<Vec<String>, UsageTotals>) -> Result<String> {
+    let rows: Vec<serde_json::Value> = rows
+        .iter()
+        .map(|(key, totals)| {
+            let mut row = serde_json::to_value(totals)?;
+            for (group, value) in group_by.iter().zip(key) {
+                row[group.name()] = serde_json::Value::String(value.clone());
+            }
+            Ok(row)
+        })
+        .collect::<Result<_>>()?;
+    Ok(serde_json::to_string_pretty(&rows)?)
+}
+
+pub fn cmd_usage(
+    config: &ProfileConfig,
+    filter: UsageFilter,
+    group_by: &[GroupBy],
+    format: OutputFormat,
+) -> Result<()> {
+    if let Some(since) = &filter.since {
+        validate_day(since, "--since")?;
+    }
+    if let Some(until) = &filter.until {
+        validate_day(until, "--until")?;
+    }
+    let group_by = if group_by.is_empty() {
+        &[GroupBy::Provider, GroupBy::Model][..]
+    } else {
+        group_by
+    };
+
+    let records = load(&default_path()?)?;
+    let rows = summarize(&records, &filter, group_by, config);
+    match format {
+        OutputFormat::Table if rows.is_empty() => println!("No usage recorded."),
+        OutputFormat::Table => print!("{}", render_table(group_by, &rows)),
+        OutputFormat::Csv => print!("{}", render_csv(group_by, &rows)),
+        OutputFormat::Json => println!("{}", render_json(group_by, &rows)?),
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn record(day: &str, provider: &str, model: &str, input: u64, output: u64) -> UsageRecord {
+        UsageRecord {
+            timestamp: format!("{}T00:00:00.000Z", day),
+            day: day.to_string(),
+            provider: provider.to_string(),
+            model: model.to_string(),
+            tier: Some("sonnet".to_string()),
+            input_tokens: input,
+            output_tokens: output,
+            cache_creation_input_tokens: 0,
+            cache_read_input_tokens: 0,
+        }
+    }
+
+    fn priced_config() -> ProfileConfig {
+        ProfileConfig {
+            prices: HashMap::from([(
+                "glm-4.7".to_string(),
+                ModelPrice {
+                    input: 1.0,
+                    output: 2.0,
+                    ..Default::default()
+                },
+            )]),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn cost_uses_price_table() {
+        let config = priced_config();
+        let r = record("2026-01-01", "glm", "glm-4.7", 1_000_000, 500_000);
+        assert_eq!(r.cost(&config.prices), Some(2.0));
+        let unpriced = record("2026-01-01", "claude", "claude-sonnet-4", 1, 1);
+        assert_eq!(unpriced.cost(&config.prices), None);
+    }
+
+    #[test]
+    fn summarize_groups_and_filters() {
+        let config = priced_config();
+        let records = vec![
+            record("2026-01-01", "glm", "glm-4.7", 100, 10),
+            record("2026-01-02", "glm", "glm-4.7", 200, 20),
+            record("2026-01-02", "claude", "claude-sonnet-4", 50, 5),
+            record("2026-02-01", "glm", "glm-4.7", 999, 99),
+        ];
+        let filter = UsageFilter {
+            until: Some("2026-01-31".to_string()),
+            ..Default::default()
+        };
+        let rows = summarize(&records, &filter, &[GroupBy::Provider], &config);
+        assert_eq!(rows.len(), 2);
+        let glm = &rows[&vec!["glm".to_string()]];
+        assert_eq!(glm.requests, 2);
+        assert_eq!(glm.input_tokens, 300);
+        assert!(glm.cost_usd.is_some());
+        let claude = &rows[&vec!["claude".to_string()]];
+        assert_eq!(claude.cost_usd, None);
+        assert_eq!(claude.unpriced_requests, 1);
+    }
+
+    #[test]
+    fn renders_csv_and_table() {
+        let config = priced_config();
+        let records = vec![record("2026-01-01", "glm", "glm-4.7", 100, 10)];
+        let group_by = [GroupBy::Day, GroupBy::Model];
+        let rows = summarize(&records, &UsageFilter::default(), &group_by, &config);
+        let csv = render_csv(&group_by, &rows);
+        assert!(csv.starts_with("day,model,requests,"));
+        assert!(csv.contains("2026-01-01,glm-4.7,1,100,10,0,0,"));
+        let table = render_table(&group_by, &rows);
+        assert!(table.lines().next().unwrap().starts_with("day"));
+        assert!(table.lines().last().unwrap().starts_with("TOTAL"));
+        let json: serde_json::Value =
+            serde_json::from_str(&render_json(&group_by, &rows).unwrap()).unwrap();
+        assert_eq!(json[0]["model"], "glm-4.7");
+        assert_eq!(json[0]["requests"], 1);
+    }
+
+    #[test]
+    fn store_roundtrip_skips_corrupt_lines() {
+        let dir = std::env::temp_dir().join(format!("cms-usage-test-{}", std::process::id()));
+        let path = dir.join("usage.jsonl");
+        let _ = std::fs::remove_file(&path);
+        let r = record("2026-01-01", "glm", "glm-4.7", 1, 2);
+        append(&path, &r).unwrap();
+        std::fs::OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .unwrap()
+            .write_all(b"{\"truncated\n")
+            .unwrap();
+        assert_eq!(load(&path).unwrap(), vec![r]);
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn day_validation() {
+        assert!(validate_day("2026-01-31", "--since").is_ok());
+        assert!(validate_day("2026-1-31", "--since").is_err());
+    }
+}
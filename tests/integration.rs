@@ -101,6 +101,36 @@ fn test_add_glm_with_api_key_without_base_url() {
         config["providers"]["glm"]["api_key"].as_str(),
         Some("sk-test")
     );
+    // Presets keep the Anthropic format unless asked otherwise.
+    assert_eq!(config["providers"]["glm"]["api_format"].as_str(), None);
+}
+
+#[test]
+fn test_add_custom_with_api_format_flag() {
+    let bin = bin_path();
+    let home = unique_home("add-api-format");
+
+    let output = Command::new(&bin)
+        .env("HOME", &home)
+        .args([
+            "add",
+            "groq",
+            "https://api.groq.com/openai/v1",
+            "gsk-test",
+            "--api-format",
+            "openai",
+        ])
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "{:?}", output);
+
+    let config_path = home.join(".claude").join("model-profiles.json");
+    let config_raw = std::fs::read_to_string(config_path).unwrap();
+    let config: serde_json::Value = serde_json::from_str(&config_raw).unwrap();
+    assert_eq!(
+        config["providers"]["groq"]["api_format"].as_str(),
+        Some("openai")
+    );
 }
 
 #[test]
@@ -175,3 +205,252 @@ fn test_add_unknown_without_base_url_fails() {
     let stderr = String::from_utf8_lossy(&output.stderr);
     assert!(stderr.contains("Missing base URL"));
 }
+
+/// A background process that is killed when the test ends, pass or fail.
+struct Running(std::process::Child);
+
+impl Drop for Running {
+    fn drop(&mut self) {
+        let _ = self.0.kill();
+        let _ = self.0.wait();
+    }
+}
+
+fn free_port() -> u16 {
+    std::net::TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port()
+}
+
+fn spawn_listening(bin: &str, home: &std::path::Path, args: &[&str], port: u16) -> Running {
+    let child = Command::new(bin)
+        .env("HOME", home)
+        .args(args)
+        .stdout(std::process::Stdio::null())
+        .stderr(std::process::Stdio::null())
+        .spawn()
+        .unwrap();
+    let running = Running(child);
+    let deadline = std::time::Instant::now() + std::time::Duration::from_secs(10);
+    while std::net::TcpStream::connect(("127.0.0.1", port)).is_err() {
+        assert!(
+            std::time::Instant::now() < deadline,
+            "{:?} did not listen on {}",
+            args,
+            port
+        );
+        std::thread::sleep(std::time::Duration::from_millis(50));
+    }
+    running
+}
+
+/// Start `mock-upstream` and a proxy in front of it. The proxy knows the
+/// mock as `mock` (Anthropic format, falling back to `mock-openai`) and as
+/// `mock-openai` (OpenAI format). Returns the proxy's port.
+fn proxy_with_mock(
+    label: &str,
+    active: &str,
+    script: Option<serde_json::Value>,
+) -> (u16, Vec<Running>) {
+    proxy_with_mock_in(&unique_home(label), active, script)
+}
+
+fn proxy_with_mock_in(
+    home: &std::path::Path,
+    active: &str,
+    script: Option<serde_json::Value>,
+) -> (u16, Vec<Running>) {
+    let bin = bin_path();
+    let mock_port = free_port();
+    let proxy_port = free_port();
+
+    let models = serde_json::json!({
+        "haiku": "mock-haiku",
+        "sonnet": "mock-sonnet",
+        "opus": "mock-opus"
+    });
+    let config = serde_json::json!({
+        "active": active,
+        "providers": {
+            "mock": {
+                "base_url": format!("http://127.0.0.1:{}", mock_port),
+                "api_key": "sk-mock",
+                "models": models,
+                "fallback": ["mock-openai"]
+            },
+            "mock-openai": {
+                "base_url": format!("http://127.0.0.1:{}/v1", mock_port),
+                "api_key": "sk-mock",
+                "models": models,
+                "api_format": "openai"
+            }
+        }
+    });
+    std::fs::create_dir_all(home.join(".claude")).unwrap();
+    std::fs::write(
+        home.join(".claude").join("model-profiles.json"),
+        serde_json::to_vec_pretty(&config).unwrap(),
+    )
+    .unwrap();
+
+    let mock_port_arg = mock_port.to_string();
+    let mut mock_args = vec!["mock-upstream", "--port", &mock_port_arg];
+    let script_path = home.join("script.json");
+    if let Some(script) = script {
+        std::fs::write(&script_path, script.to_string()).unwrap();
+        mock_args.extend(["--script", script_path.to_str().unwrap()]);
+    }
+    let mock = spawn_listening(&bin, home, &mock_args, mock_port);
+    let proxy_port_arg = proxy_port.to_string();
+    let proxy = spawn_listening(
+        &bin,
+        home,
+        &["start", "--port", &proxy_port_arg, "--foreground"],
+        proxy_port,
+    );
+    (proxy_port, vec![proxy, mock])
+}
+
+async fn post_messages(port: u16, body: serde_json::Value) -> (u16, String) {
+    let response = reqwest::Client::new()
+        .post(format!("http://127.0.0.1:{}/v1/messages", port))
+        .json(&body)
+        .send()
+        .await
+        .unwrap();
+    let status = response.status().as_u16();
+    (status, response.text().await.unwrap())
+}
+
+/// Concatenated `text_delta`s of an Anthropic SSE body.
+fn streamed_text(sse: &str) -> String {
+    sse.lines()
+        .filter_map(|line| line.strip_prefix("data: "))
+        .filter_map(|data| serde_json::from_str::<serde_json::Value>(data).ok())
+        .filter_map(|event| event["delta"]["text"].as_str().map(str::to_string))
+        .collect()
+}
+
+#[tokio::test]
+async fn test_proxy_rewrites_model_for_anthropic_upstream() {
+    let (port, _running) = proxy_with_mock("mock-anthropic", "mock", None);
+
+    let (status, body) = post_messages(
+        port,
+        serde_json::json!({
+            "model": "claude-sonnet-4-5",
+            "max_tokens": 16,
+            "messages": [{"role": "user", "content": "ping"}]
+        }),
+    )
+    .await;
+    assert_eq!(status, 200, "{}", body);
+    let message: serde_json::Value = serde_json::from_str(&body).unwrap();
+    assert_eq!(message["model"], "mock-sonnet");
+    assert_eq!(message["content"][0]["text"], "echo: ping");
+}
+
+#[tokio::test]
+async fn test_proxy_translates_openai_stream() {
+    let (port, _running) = proxy_with_mock("mock-openai-stream", "mock-openai", None);
+
+    let (status, body) = post_messages(
+        port,
+        serde_json::json!({
+            "model": "claude-opus-4",
+            "max_tokens": 16,
+            "stream": true,
+            "messages": [{"role": "user", "content": "hello there"}]
+        }),
+    )
+    .await;
+    assert_eq!(status, 200, "{}", body);
+    assert_eq!(streamed_text(&body), "echo: hello there");
+    assert!(body.contains("event: message_stop"));
+}
+
+#[tokio::test]
+async fn test_proxy_translates_openai_tool_calls() {
+    let script = serde_json::json!([
+        {"tool_call": {"name": "get_weather", "input": {"city": "Paris"}}}
+    ]);
+    let (port, _running) = proxy_with_mock("mock-openai-tools", "mock-openai", Some(script));
+
+    let (status, body) = post_messages(
+        port,
+        serde_json::json!({
+            "model": "claude-sonnet-4-5",
+            "max_tokens": 16,
+            "messages": [{"role": "user", "content": "weather?"}]
+        }),
+    )
+    .await;
+    assert_eq!(status, 200, "{}", body);
+    let message: serde_json::Value = serde_json::from_str(&body).unwrap();
+    assert_eq!(message["stop_reason"], "tool_use");
+    assert_eq!(message["content"][0]["name"], "get_weather");
+    assert_eq!(message["content"][0]["input"]["city"], "Paris");
+}
+
+#[tokio::test]
+async fn test_proxy_falls_back_on_injected_error() {
+    let script = serde_json::json!([
+        {"error": {"status": 503}},
+        {"text": "recovered"}
+    ]);
+    let (port, _running) = proxy_with_mock("mock-fallback", "mock", Some(script));
+
+    let (status, body) = post_messages(
+        port,
+        serde_json::json!({
+            "model": "claude-haiku-4",
+            "max_tokens": 16,
+            "messages": [{"role": "user", "content": "hi"}]
+        }),
+    )
+    .await;
+    assert_eq!(status, 200, "{}", body);
+    let message: serde_json::Value = serde_json::from_str(&body).unwrap();
+    assert_eq!(message["content"][0]["text"], "recovered");
+    assert_eq!(message["model"], "mock-haiku");
+}
+
+#[tokio::test]
+async fn test_admin_switch_rejects_invalid_config() {
+    let home = unique_home("admin-invalid");
+    let (_port, _running) = proxy_with_mock_in(&home, "mock", None);
+
+    // Break the config on disk the way a hand edit might: a fallback to a
+    // provider that does not exist.
+    let config_path = home.join(".claude").join("model-profiles.json");
+    let mut config: serde_json::Value =
+        serde_json::from_slice(&std::fs::read(&config_path).unwrap()).unwrap();
+    config["providers"]["mock-openai"]["fallback"] = serde_json::json!(["missing"]);
+    std::fs::write(&config_path, serde_json::to_vec_pretty(&config).unwrap()).unwrap();
+
+    // The endpoint is published just after the port starts listening.
+    let endpoint_path = home.join(".claude").join("model-switch-admin.json");
+    let deadline = std::time::Instant::now() + std::time::Duration::from_secs(10);
+    while !endpoint_path.exists() {
+        assert!(std::time::Instant::now() < deadline, "no admin endpoint");
+        std::thread::sleep(std::time::Duration::from_millis(50));
+    }
+    let endpoint: serde_json::Value =
+        serde_json::from_slice(&std::fs::read(&endpoint_path).unwrap()).unwrap();
+    let response = reqwest::Client::new()
+        .put(format!("{}/_cms/active", endpoint["url"].as_str().unwrap()))
+        .bearer_auth(endpoint["token"].as_str().unwrap())
+        .json(&serde_json::json!({"provider": "mock-openai"}))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status().as_u16(), 400);
+    let body: serde_json::Value = response.json().await.unwrap();
+    assert!(body["errors"][0].as_str().unwrap().contains("missing"));
+
+    let saved: serde_json::Value =
+        serde_json::from_slice(&std::fs::read(&config_path).unwrap()).unwrap();
+    assert_eq!(saved["active"], "mock");
+}